@@ -0,0 +1,69 @@
+// Measures how random point lookups scale with the number of reading threads.
+// Run with `cargo bench --bench read_scaling`
+use indydb::{DBParams, DB};
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+const DB_NAME: &str = "target/benchdb_read_scaling";
+const NUM_KEYS: u64 = 100_000;
+const READS_PER_THREAD: u64 = 200_000;
+
+fn key(i: u64) -> String {
+    format!("key{:08}", i)
+}
+
+// xorshift generator, so that every thread reads its own sequence of keys
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn main() {
+    let _ = fs::remove_dir_all(DB_NAME);
+    let mut db_params = DBParams::new();
+    db_params.write_buffer_size = 1 << 20;
+    let db = DB::open(DB_NAME, db_params).unwrap();
+    for i in 0..NUM_KEYS {
+        db.put(key(i), format!("value{:092}", i)).unwrap();
+    }
+    db.close().unwrap();
+
+    let db = Arc::new(DB::open(DB_NAME, DBParams::new()).unwrap());
+    // warm up the caches of log tables and blocks
+    for i in 0..NUM_KEYS {
+        db.get(key(i)).unwrap().unwrap();
+    }
+
+    println!("{:>8} {:>14} {:>10}", "threads", "reads/sec", "speedup");
+    let mut base = 0.0;
+    for &num_threads in [1, 2, 4, 8, 16].iter() {
+        let start = Instant::now();
+        let handles: Vec<_> = (0..num_threads)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let mut state = 0x9E37_79B9_7F4A_7C15 ^ (t as u64 + 1);
+                    for _ in 0..READS_PER_THREAD {
+                        let i = next_random(&mut state) % NUM_KEYS;
+                        assert!(db.get(key(i)).unwrap().is_some());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let reads_per_sec = (num_threads as u64 * READS_PER_THREAD) as f64 / start.elapsed().as_secs_f64();
+        if num_threads == 1 {
+            base = reads_per_sec;
+        }
+        println!("{:>8} {:>14.0} {:>9.2}x", num_threads, reads_per_sec, reads_per_sec / base);
+    }
+
+    drop(db);
+    fs::remove_dir_all(DB_NAME).unwrap();
+}
@@ -1,10 +1,17 @@
 use std::borrow::Borrow;
 use std::clone::Clone;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::marker::Copy;
 use std::mem;
 use std::ptr;
+use std::sync::Mutex;
+
+use crate::errors::Result;
+
+// maximum number of shards of a ShardedLRUCache
+const NUM_SHARDS: usize = 16;
 
 struct RawLink<T> {
     p: *mut T,
@@ -14,7 +21,7 @@ impl<T> Copy for RawLink<T> {}
 
 impl<T> Clone for RawLink<T> {
     fn clone(&self) -> Self {
-        RawLink { p: self.p }
+        *self
     }
 }
 
@@ -64,38 +71,60 @@ where
 struct LRUEntry<K, V> {
     key: K,
     val: V,
+    // share of the capacity of the cache taken by the entry
+    charge: usize,
     next: RawLink<LRUEntry<K, V>>,
     prev: RawLink<LRUEntry<K, V>>,
 }
 
 impl<K, V> LRUEntry<K, V> {
-    fn new(key: K, val: V) -> Self {
+    fn new(key: K, val: V, charge: usize) -> Self {
         LRUEntry {
             key,
             val,
+            charge,
             prev: RawLink::none(),
             next: RawLink::none(),
         }
     }
 }
 
+// LRU cache whose capacity bounds the total charge of its entries, which is the number of entries
+// unless entries are put with an explicit charge. The number of entries may also be bounded on its own
 pub struct LRUCache<K, V> {
     map: HashMap<KeyRef<K>, Box<LRUEntry<K, V>>>,
     cap: usize,
+    // maximum number of entries
+    max_len: usize,
+    // total charge of the entries
+    usage: usize,
     head: RawLink<LRUEntry<K, V>>,
     tail: RawLink<LRUEntry<K, V>>,
 }
 
+// The raw links only ever point into entries owned by the map, so the cache can be
+// moved across threads as long as its keys and values can.
+unsafe impl<K: Send, V: Send> Send for LRUCache<K, V> {}
+unsafe impl<K: Sync, V: Sync> Sync for LRUCache<K, V> {}
+
 impl<K: Hash + Eq, V> LRUCache<K, V> {
+    #[cfg(test)]
     pub fn new(cap: usize) -> Self {
+        LRUCache::with_max_len(cap, usize::MAX)
+    }
+
+    // cache that holds at most max_len entries whatever their charge
+    pub fn with_max_len(cap: usize, max_len: usize) -> Self {
         let mut cache = LRUCache {
-            map: HashMap::with_capacity(cap),
+            map: HashMap::new(),
             cap,
+            max_len,
+            usage: 0,
             head: RawLink {
-                p: unsafe { Box::into_raw(Box::new(mem::MaybeUninit::<LRUEntry<K, V>>::uninit().assume_init())) },
+                p: Box::into_raw(Box::new(mem::MaybeUninit::<LRUEntry<K, V>>::uninit())) as *mut _,
             },
             tail: RawLink {
-                p: unsafe { Box::into_raw(Box::new(mem::MaybeUninit::<LRUEntry<K, V>>::uninit().assume_init())) },
+                p: Box::into_raw(Box::new(mem::MaybeUninit::<LRUEntry<K, V>>::uninit())) as *mut _,
             },
         };
 
@@ -119,32 +148,57 @@ impl<K: Hash + Eq, V> LRUCache<K, V> {
         }
     }
 
+    #[cfg(test)]
     pub fn put(&mut self, key: K, val: V) -> Option<V> {
-        if let Some(entry) = self.map.get_mut(&key) {
-            let prev_val = mem::replace(&mut (*entry).val, val);
+        self.put_with_charge(key, val, 1)
+    }
+
+    // Puts the entry taking the given share of the capacity, evicting the least recently used entries
+    // until the cache is within its capacity and its maximum number of entries. An entry larger than the capacity
+    // is not kept
+    pub fn put_with_charge(&mut self, key: K, val: V, charge: usize) -> Option<V> {
+        if charge > self.cap {
+            return self.remove(&key);
+        }
+        let prev_val = if let Some(entry) = self.map.get_mut(&key) {
+            let prev_val = mem::replace(&mut entry.val, val);
+            self.usage = self.usage - entry.charge + charge;
+            entry.charge = charge;
             let entry_ptr = RawLink::some(&mut **entry);
             self.detach(entry_ptr);
             self.attach(entry_ptr);
             Some(prev_val)
         } else {
-            let mut entry = if self.map.len() == self.cap {
-                let mut last_entry_ptr = self.tail.resolve_mut().prev;
-                let last_entry = last_entry_ptr.resolve_mut();
-                self.map.remove(&last_entry.key).unwrap();
-
-                last_entry.key = key;
-                last_entry.val = val;
-
-                self.detach(last_entry_ptr);
-                last_entry_ptr.to_box()
-            } else {
-                Box::new(LRUEntry::new(key, val))
-            };
-
+            let mut entry = Box::new(LRUEntry::new(key, val, charge));
             self.attach(RawLink::some(&mut *entry));
-            self.map.insert(KeyRef { k: &(*entry).key }, entry);
+            self.map.insert(KeyRef { k: &entry.key }, entry);
+            self.usage += charge;
             None
+        };
+
+        while self.usage > self.cap || self.map.len() > self.max_len {
+            let mut last_entry_ptr = self.tail.resolve_mut().prev;
+            self.detach(last_entry_ptr);
+            let last_entry = self.map.remove(&last_entry_ptr.resolve_mut().key).unwrap();
+            self.usage -= last_entry.charge;
         }
+        prev_val
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        KeyRef<K>: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut entry = self.map.remove(key)?;
+        self.detach(RawLink::some(&mut *entry));
+        self.usage -= entry.charge;
+        Some(entry.val)
+    }
+
+    // total charge of the entries in the cache
+    pub fn usage(&self) -> usize {
+        self.usage
     }
 
     fn detach(&mut self, mut node_ptr: RawLink<LRUEntry<K, V>>) {
@@ -155,6 +209,7 @@ impl<K: Hash + Eq, V> LRUCache<K, V> {
 
     fn attach(&mut self, mut node_ptr: RawLink<LRUEntry<K, V>>) {
         let node = node_ptr.resolve_mut();
+        node.prev = self.head;
         let head = self.head.resolve_mut();
         node.next = head.next;
 
@@ -165,6 +220,74 @@ impl<K: Hash + Eq, V> LRUCache<K, V> {
     }
 }
 
+impl<K, V> Drop for LRUCache<K, V> {
+    fn drop(&mut self) {
+        // the entries are freed along with the map, while the sentinels were allocated uninitialised, so they are
+        // freed without dropping their contents
+        unsafe {
+            drop(Box::from_raw(self.head.p as *mut mem::MaybeUninit<LRUEntry<K, V>>));
+            drop(Box::from_raw(self.tail.p as *mut mem::MaybeUninit<LRUEntry<K, V>>));
+        }
+    }
+}
+
+// LRU cache split by the hash of the key into independently locked shards, so that threads looking up keys
+// of different shards never wait for each other. Every shard tracks the recency of its own entries and gets an
+// equal part of the capacity, so the total charge of the entries never exceeds the capacity
+pub struct ShardedLRUCache<K, V> {
+    shards: Vec<Mutex<LRUCache<K, V>>>,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedLRUCache<K, V> {
+    pub fn new(cap: usize) -> Self {
+        ShardedLRUCache::with_max_len(cap, usize::MAX)
+    }
+
+    // cache that holds at most max_len entries whatever their charge, every shard getting an equal part of them
+    pub fn with_max_len(cap: usize, max_len: usize) -> Self {
+        let num_shards = NUM_SHARDS.min(cap).min(max_len).max(1);
+        ShardedLRUCache {
+            shards: (0..num_shards)
+                .map(|_| Mutex::new(LRUCache::with_max_len(cap / num_shards, max_len / num_shards)))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LRUCache<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        Ok((self.shard(key).lock()?).get(key).cloned())
+    }
+
+    #[cfg(test)]
+    pub fn put(&self, key: K, val: V) -> Result<()> {
+        (self.shard(&key).lock()?).put(key, val);
+        Ok(())
+    }
+
+    pub fn put_with_charge(&self, key: K, val: V, charge: usize) -> Result<()> {
+        (self.shard(&key).lock()?).put_with_charge(key, val, charge);
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        Ok((self.shard(key).lock()?).remove(key))
+    }
+
+    // total charge of the entries in the cache
+    pub fn usage(&self) -> Result<usize> {
+        let mut usage = 0;
+        for shard in self.shards.iter() {
+            usage += (shard.lock()?).usage();
+        }
+        Ok(usage)
+    }
+}
+
 impl<T> RawLink<T> {
     fn none() -> RawLink<T> {
         RawLink { p: ptr::null_mut() }
@@ -174,10 +297,6 @@ impl<T> RawLink<T> {
         RawLink { p: n as *mut T }
     }
 
-    fn to_box(self) -> Box<T> {
-        unsafe { Box::from_raw(self.p) }
-    }
-
     fn resolve_mut(&mut self) -> &mut T {
         unsafe { &mut *self.p }
     }
@@ -185,8 +304,10 @@ impl<T> RawLink<T> {
 
 #[cfg(test)]
 mod tests {
-    use super::LRUCache;
+    use super::{LRUCache, ShardedLRUCache, NUM_SHARDS};
     use std::fmt::Debug;
+    use std::sync::Arc;
+    use std::thread;
 
     fn assert_opt_eq<V: PartialEq + Debug>(opt: Option<&V>, v: V) {
         assert!(opt.is_some());
@@ -205,4 +326,83 @@ mod tests {
         assert!(cache.get(&"paris").is_none());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_get_moves_to_front() {
+        let mut cache = LRUCache::new(2);
+
+        cache.put("hello", "world");
+        cache.put("lorem", "ipsum");
+        assert_opt_eq(cache.get(&"hello"), "world");
+        assert_opt_eq(cache.get(&"hello"), "world");
+
+        // "lorem" is the least recently used entry
+        cache.put("paris", "france");
+        assert!(cache.get(&"lorem").is_none());
+        assert_opt_eq(cache.get(&"hello"), "world");
+        assert_opt_eq(cache.get(&"paris"), "france");
+    }
+
+    #[test]
+    fn test_charge() {
+        let mut cache = LRUCache::new(10);
+
+        cache.put_with_charge("hello", "world", 4);
+        cache.put_with_charge("lorem", "ipsum", 4);
+        assert_eq!(cache.usage(), 8);
+        assert_opt_eq(cache.get(&"hello"), "world");
+
+        // "lorem" is evicted to make room
+        cache.put_with_charge("paris", "france", 4);
+        assert_eq!(cache.usage(), 8);
+        assert!(cache.get(&"lorem").is_none());
+
+        // an entry larger than the cache is not kept and does not evict the other entries
+        cache.put_with_charge("large", "entry", 11);
+        assert!(cache.get(&"large").is_none());
+        assert_eq!(cache.usage(), 8);
+
+        assert_eq!(cache.remove(&"hello"), Some("world"));
+        assert_eq!(cache.usage(), 4);
+
+        // with a maximum number of entries, entries are evicted even though the cache is within its capacity
+        let mut cache = LRUCache::with_max_len(10, 2);
+        cache.put_with_charge("hello", "world", 1);
+        cache.put_with_charge("lorem", "ipsum", 1);
+        cache.put_with_charge("paris", "france", 1);
+        assert_eq!(cache.usage(), 2);
+        assert!(cache.get(&"hello").is_none());
+        assert_opt_eq(cache.get(&"paris"), "france");
+    }
+
+    #[test]
+    fn test_sharded() {
+        // every shard can hold all the entries, so none is evicted whatever shards the keys hash to
+        let cache = Arc::new(ShardedLRUCache::new(NUM_SHARDS * 4000));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        cache.put(t * 1000 + i, i).unwrap();
+                        assert_eq!(cache.get(&(t * 1000 + i)).unwrap(), Some(i));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(cache.usage().unwrap(), 4000);
+        for key in 0..4000 {
+            assert_eq!(cache.get(&key).unwrap(), Some(key % 1000));
+        }
+
+        // a cache smaller than the number of shards still stays within its capacity
+        let cache = ShardedLRUCache::new(2);
+        for i in 0..10 {
+            cache.put(i, i).unwrap();
+        }
+        assert!(cache.usage().unwrap() <= 2);
+        assert_eq!(cache.get(&9).unwrap(), Some(9));
+    }
+}
@@ -0,0 +1,630 @@
+use std::fs;
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+
+use crate::cache::ShardedLRUCache;
+use crate::errors::{Error, Result};
+use crate::filter;
+use crate::iterator::{DBIterator, Source};
+use crate::manifest::{Manifest, TableMeta, VersionEdit};
+use crate::memtable::MemValue;
+use crate::params::{CompactionStrategy, DBParams};
+use crate::table::{Table, TableBuilder};
+
+/// Application logic run by compactions on the entries they merge, to drop or rewrite entries without
+/// issuing writes, for example to expire old entries
+pub trait CompactionFilter: Send + Sync {
+    /// Decides what becomes of a key and its newest value merged by a compaction, deleted keys excluded.
+    /// Compactions run in a background thread while the db is used, and merge every entry a number of times
+    /// as it moves to deeper levels, so the decision should only depend on the key and value
+    fn filter(&self, key: &[u8], value: &[u8]) -> FilterDecision;
+}
+
+/// What a compaction filter decides for an entry
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterDecision {
+    /// keep the entry as it is
+    Keep,
+    /// remove the key from the db, as if it had been deleted
+    Remove,
+    /// replace the value of the key
+    Replace(Vec<u8>),
+}
+
+/// Outcome of a compaction of a range of keys
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactionStats {
+    /// number of log tables merged
+    pub tables_read: usize,
+    /// number of log tables written
+    pub tables_written: usize,
+    /// size in bytes of the data and index tables of the merged log tables
+    pub bytes_read: u64,
+    /// size in bytes of the data and index tables written
+    pub bytes_written: u64,
+    /// size in bytes freed on disk, taken up by overwritten values and deleted keys
+    pub bytes_reclaimed: u64,
+}
+
+// Log tables picked to be merged into a deeper level
+pub struct Compaction {
+    // tables merged by the compaction, ordered from newest to oldest
+    inputs: Vec<TableMeta>,
+    // level the merged tables are written to
+    output_level: usize,
+    // live tables that are not merged but hold data older than the inputs.
+    // A delete marker is only dropped if none of them may contain its key
+    older: Vec<TableMeta>,
+}
+
+impl Compaction {
+    // picks the tables to merge next according to the compaction strategy, None if the db needs no compaction
+    pub fn pick(manifest: &Manifest, db_params: &DBParams) -> Option<Compaction> {
+        match db_params.compaction_strategy {
+            CompactionStrategy::Leveled => Compaction::pick_leveled(manifest, db_params),
+            CompactionStrategy::SizeTiered => Compaction::pick_size_tiered(manifest, db_params),
+        }
+    }
+
+    // Picks the level that exceeds its limit the most, level 0 being scored by its number of tables and every
+    // deeper level but the last by its size in bytes, and merges it into the next level.
+    // All the tables of level 0 are merged, since their key ranges may overlap, while of a deeper level only the
+    // table overlapping the fewest bytes of the next level for its own size is merged. The tables of the next
+    // level overlapping the merged tables are merged along with them, which keeps the key ranges of the next level
+    // disjoint
+    fn pick_leveled(manifest: &Manifest, db_params: &DBParams) -> Option<Compaction> {
+        let num_levels = db_params.num_levels.max(2);
+        let mut level = 0;
+        let mut max_score =
+            manifest.level(0).len() as f64 / db_params.level0_compaction_trigger.max(1) as f64;
+        let mut max_bytes = db_params.max_bytes_for_level_base.max(1) as f64;
+        for l in 1..num_levels - 1 {
+            let score = level_size(manifest.level(l)) as f64 / max_bytes;
+            if score > max_score {
+                level = l;
+                max_score = score;
+            }
+            max_bytes *= db_params.max_bytes_for_level_multiplier.max(1) as f64;
+        }
+        if max_score < 1.0 {
+            return None;
+        }
+
+        let next_level = manifest.level(level + 1);
+        let mut inputs: Vec<TableMeta> = if level == 0 {
+            manifest.level(0).iter().rev().cloned().collect()
+        } else {
+            let overlap_ratio = |table: &TableMeta| {
+                let overlapping = overlapping_tables(next_level, &table.smallest, &table.largest);
+                level_size(&overlapping) as f64 / table.size.max(1) as f64
+            };
+            let table = manifest
+                .level(level)
+                .iter()
+                .map(|table| (overlap_ratio(table), table))
+                .min_by(|(a, _), (b, _)| a.total_cmp(b))?
+                .1;
+            vec![table.clone()]
+        };
+        let smallest = inputs.iter().map(|table| table.smallest.clone()).min()?;
+        let largest = inputs.iter().map(|table| table.largest.clone()).max()?;
+        inputs.extend(overlapping_tables(next_level, &smallest, &largest));
+
+        let older = (level + 2..manifest.num_levels())
+            .flat_map(|l| manifest.level(l).iter().cloned())
+            .collect();
+        Some(Compaction {
+            inputs,
+            output_level: level + 1,
+            older,
+        })
+    }
+
+    // Picks sorted runs of similar size once there are level0_compaction_trigger runs, every table of level 0
+    // and every non-empty deeper level being a run. Starting from the newest run, the runs are merged with the
+    // next older run as long as it is at most size_tiered_size_ratio percent larger than their total size.
+    // Without such a window of at least size_tiered_min_merge_width runs, the newest runs are merged until
+    // fewer than level0_compaction_trigger runs are left.
+    // The merged runs are written to the level of the oldest of them. Tables of level 0 are newer than all the
+    // deeper levels, so they are only merged on their own together with the oldest table of level 0, and are
+    // then written to the deepest empty level above the other runs, or merged with level 1 if it is not empty
+    fn pick_size_tiered(manifest: &Manifest, db_params: &DBParams) -> Option<Compaction> {
+        // sorted runs from newest to oldest, along with their level
+        let mut runs: Vec<(usize, &[TableMeta])> = manifest
+            .level(0)
+            .iter()
+            .rev()
+            .map(|table| (0, std::slice::from_ref(table)))
+            .collect();
+        runs.extend(
+            (1..manifest.num_levels())
+                .filter(|&level| !manifest.level(level).is_empty())
+                .map(|level| (level, manifest.level(level))),
+        );
+        let trigger = db_params.level0_compaction_trigger.max(2);
+        if runs.len() < trigger {
+            return None;
+        }
+
+        let num_level0_tables = manifest.level(0).len();
+        let min_width = db_params.size_tiered_min_merge_width.max(2);
+        let mut window = None;
+        for start in 0..runs.len() {
+            let mut size = level_size(runs[start].1);
+            let mut end = start + 1;
+            while end < runs.len()
+                && level_size(runs[end].1) * 100 <= size * (100 + db_params.size_tiered_size_ratio)
+            {
+                size += level_size(runs[end].1);
+                end += 1;
+            }
+            if end - start >= min_width && end >= num_level0_tables {
+                window = Some((start, end));
+                break;
+            }
+        }
+        let (start, mut end) = window.unwrap_or_else(|| {
+            let width = (runs.len() + 1 - trigger)
+                .max(min_width)
+                .max(num_level0_tables);
+            (0, width.min(runs.len()))
+        });
+
+        let output_level = match runs[end - 1].0 {
+            0 => match runs
+                .get(end)
+                .map_or(db_params.num_levels.max(2), |run| run.0)
+            {
+                1 => {
+                    end += 1;
+                    1
+                }
+                level => level - 1,
+            },
+            level => level,
+        };
+        let inputs = runs[start..end]
+            .iter()
+            .flat_map(|run| run.1.iter().cloned())
+            .collect();
+        let older = (output_level + 1..manifest.num_levels())
+            .flat_map(|l| manifest.level(l).iter().cloned())
+            .collect();
+        Some(Compaction {
+            inputs,
+            output_level,
+            older,
+        })
+    }
+
+    // Picks the tables overlapping the range from start to end, None being unbounded, and the tables overlapping
+    // those until no table left out overlaps the merged tables. The merged tables then hold every version of their
+    // keys, so all the delete markers are dropped, and they can be written to any level without overlapping the
+    // tables of that level. They are written to the last level, or to the deepest level they come from if deeper.
+    // Returns None if no table overlaps the range
+    pub fn pick_range(
+        manifest: &Manifest,
+        db_params: &DBParams,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Option<Compaction> {
+        let mut start = start.map(|key| key.to_vec());
+        let mut end = end.map(|key| key.to_vec());
+        loop {
+            // tables from newest to oldest
+            let inputs: Vec<TableMeta> = manifest
+                .level(0)
+                .iter()
+                .rev()
+                .chain((1..manifest.num_levels()).flat_map(|level| manifest.level(level)))
+                .filter(|table| table.overlaps(start.as_deref(), end.as_deref()))
+                .cloned()
+                .collect();
+            let smallest = inputs.iter().map(|table| table.smallest.clone()).min()?;
+            let largest = inputs.iter().map(|table| table.largest.clone()).max()?;
+            // the merged tables may extend past the range and overlap more tables
+            let wider_start = start.clone().map(|start| start.min(smallest));
+            let wider_end = end.clone().map(|end| end.max(largest));
+            if wider_start == start && wider_end == end {
+                let last_level = db_params.num_levels.max(2) - 1;
+                let output_level = inputs
+                    .iter()
+                    .map(|table| table.level)
+                    .max()?
+                    .max(last_level);
+                return Some(Compaction {
+                    inputs,
+                    output_level,
+                    older: Vec::new(),
+                });
+            }
+            start = wider_start;
+            end = wider_end;
+        }
+    }
+
+    // whether a delete marker of the key can be dropped, because no older table may contain the key
+    fn may_drop_delete(&self, key: &[u8]) -> bool {
+        !self
+            .older
+            .iter()
+            .any(|table| table.overlaps(Some(key), Some(key)))
+    }
+}
+
+// total size in bytes of the tables
+fn level_size(tables: &[TableMeta]) -> u64 {
+    tables.iter().map(|table| table.size).sum()
+}
+
+// tables of a level below 0 whose key range overlaps the range from smallest to largest
+fn overlapping_tables(tables: &[TableMeta], smallest: &[u8], largest: &[u8]) -> Vec<TableMeta> {
+    tables
+        .iter()
+        .filter(|table| table.overlaps(Some(smallest), Some(largest)))
+        .cloned()
+        .collect()
+}
+
+// Compactor merges log tables into new tables of a deeper level, keeping only the newest version of every key
+// and dropping the delete markers that no longer hide any older value.
+// The merged tables are swapped for the new ones in a single manifest edit, so readers see either of them,
+// and their files are removed once no reader is opening them. Readers that already opened them keep
+// reading the open files, so snapshots and iterators are not affected by compactions
+pub struct Compactor {
+    db_name: String,
+    manifest: Arc<RwLock<Manifest>>,
+    // cache of the log tables, the merged tables are evicted from it
+    cache: Arc<ShardedLRUCache<u64, Arc<Table>>>,
+    // held by readers from the time they look up the live tables until they have opened them,
+    // and exclusively by the compactor while it removes the files of the merged tables
+    table_files: Arc<RwLock<()>>,
+    // held while a compaction runs, so that two compactions never merge the same tables
+    running: Mutex<()>,
+    // number of compactions run since the db was opened
+    num_compactions: AtomicUsize,
+    // set once a compaction has failed, after which no more compactions are run
+    failed: Mutex<bool>,
+    // notified after every compaction, wakes up the flushes waiting for level 0 to shrink
+    compacted: Condvar,
+    db_params: DBParams,
+    sync: bool,
+}
+
+impl Compactor {
+    pub fn new(
+        db_name: &str,
+        manifest: Arc<RwLock<Manifest>>,
+        cache: Arc<ShardedLRUCache<u64, Arc<Table>>>,
+        table_files: Arc<RwLock<()>>,
+        db_params: DBParams,
+        sync: bool,
+    ) -> Compactor {
+        Compactor {
+            db_name: String::from(db_name),
+            manifest,
+            cache,
+            table_files,
+            running: Mutex::new(()),
+            num_compactions: AtomicUsize::new(0),
+            failed: Mutex::new(false),
+            compacted: Condvar::new(),
+            db_params,
+            sync,
+        }
+    }
+
+    // runs compactions until the db no longer needs any
+    pub fn compact_while_needed(&self) -> Result<()> {
+        let _running = self.running.lock()?;
+        loop {
+            let compaction = Compaction::pick(&*self.manifest.read()?, &self.db_params);
+            let result = match compaction {
+                Some(compaction) => self.run_guarded(&compaction).map(|_| ()),
+                None => return Ok(()),
+            };
+            let mut failed = self.failed.lock()?;
+            *failed |= result.is_err();
+            self.compacted.notify_all();
+            drop(failed);
+            result?;
+        }
+    }
+
+    // waits until level 0 holds fewer than level0_stop_writes_trigger tables, unless compactions have failed
+    pub fn wait_for_level0(&self) -> Result<()> {
+        // level 0 is only compacted once it reaches the compaction trigger, and a single sorted run is never
+        // compacted by size-tiered compaction
+        let params = &self.db_params;
+        let max_tables = params
+            .level0_stop_writes_trigger
+            .max(params.level0_compaction_trigger)
+            .max(2);
+        let mut failed = self.failed.lock()?;
+        while !*failed && (self.manifest.read()?).level(0).len() >= max_tables {
+            failed = self.compacted.wait(failed)?;
+        }
+        Ok(())
+    }
+
+    // Merges all the tables overlapping the range from start to end, None being unbounded, waiting for the
+    // compaction in progress if any
+    pub fn compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<CompactionStats> {
+        let _running = self.running.lock()?;
+        let compaction =
+            Compaction::pick_range(&*self.manifest.read()?, &self.db_params, start, end);
+        match compaction {
+            Some(compaction) => self.run_guarded(&compaction),
+            None => Ok(CompactionStats::default()),
+        }
+    }
+
+    pub fn num_compactions(&self) -> usize {
+        self.num_compactions.load(Ordering::Relaxed)
+    }
+
+    // stops the compactions for good once the running one, if any, has finished
+    #[cfg(test)]
+    pub(crate) fn stop(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.running.lock().unwrap()
+    }
+
+    // Runs the compaction, turning a panic of the compaction filter into an error so that the flushes
+    // waiting for level 0 to shrink are released and the db can still be closed
+    fn run_guarded(&self, compaction: &Compaction) -> Result<CompactionStats> {
+        panic::catch_unwind(AssertUnwindSafe(|| self.run(compaction)))
+            .unwrap_or(Err(Error::BackgroundCompactionError))
+    }
+
+    // merges the input tables into new tables of the output level and swaps them in
+    fn run(&self, compaction: &Compaction) -> Result<CompactionStats> {
+        let mut sources = Vec::with_capacity(compaction.inputs.len());
+        for input in compaction.inputs.iter() {
+            let table = Table::open(&self.db_name, input.file_no, None, false, true)?;
+            sources.push(Source::Table(Arc::new(table)));
+        }
+        let mut iter = DBIterator::new(sources, Bound::Unbounded, Bound::Unbounded, true);
+
+        let mut outputs = Vec::new();
+        let mut table_builder: Option<TableBuilder> = None;
+        while let Some(entry) = iter.next_entry() {
+            let (key, mut value) = entry?;
+            if let (MemValue::Value(ref val), Some(ref filter)) =
+                (&value, &self.db_params.compaction_filter)
+            {
+                match filter.filter(&key, val) {
+                    FilterDecision::Keep => (),
+                    // older versions of the key may be left in tables that are not merged, so the key is removed
+                    // like a deleted key
+                    FilterDecision::Remove => value = MemValue::Delete,
+                    FilterDecision::Replace(new_value) => value = MemValue::Value(new_value),
+                }
+            }
+            if let MemValue::Delete = value {
+                if compaction.may_drop_delete(&key) {
+                    continue;
+                }
+            }
+            let builder = match table_builder {
+                Some(ref mut builder) => builder,
+                None => {
+                    let file_no = (self.manifest.write()?).new_file_no();
+                    let bits_per_key = self.db_params.bloom_bits_per_key;
+                    table_builder.insert(TableBuilder::new(
+                        &self.db_name,
+                        file_no,
+                        compaction.output_level,
+                        bits_per_key,
+                    ))
+                }
+            };
+            builder.add(&key, &value)?;
+            if builder.data_size() >= self.db_params.target_table_size {
+                outputs.push(builder.flush(self.sync)?);
+                table_builder = None;
+            }
+        }
+        if let Some(ref mut builder) = table_builder {
+            outputs.push(builder.flush(self.sync)?);
+        }
+
+        let mut edits: Vec<VersionEdit> = compaction
+            .inputs
+            .iter()
+            .map(|input| VersionEdit::RemoveTable(input.file_no))
+            .collect();
+        let bytes_read = level_size(&compaction.inputs);
+        let bytes_written = level_size(&outputs);
+        let stats = CompactionStats {
+            tables_read: compaction.inputs.len(),
+            tables_written: outputs.len(),
+            bytes_read,
+            bytes_written,
+            bytes_reclaimed: bytes_read.saturating_sub(bytes_written),
+        };
+        edits.extend(outputs.into_iter().map(VersionEdit::AddTable));
+        (self.manifest.write()?).log_and_apply(edits)?;
+        self.remove_tables(&compaction.inputs)?;
+        self.num_compactions.fetch_add(1, Ordering::Relaxed);
+        Ok(stats)
+    }
+
+    // evicts the tables, which are no longer part of the db, from the cache and removes their files
+    fn remove_tables(&self, tables: &[TableMeta]) -> Result<()> {
+        for table in tables {
+            self.cache.remove(&table.file_no)?;
+        }
+        let _table_files = self.table_files.write()?;
+        for table in tables {
+            fs::remove_file(format!("{}/{}.dt", self.db_name, table.file_no))?;
+            fs::remove_file(format!("{}/{}.ix", self.db_name, table.file_no))?;
+            let filter_file = filter::filter_file_name(&self.db_name, table.file_no);
+            if fs::metadata(&filter_file).is_ok() {
+                fs::remove_file(filter_file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compaction;
+    use crate::manifest::{Manifest, TableMeta, VersionEdit};
+    use crate::params::{CompactionStrategy, DBParams};
+    use std::fs;
+
+    #[test]
+    fn test_pick_leveled() {
+        let db_name = "target/testcompaction1";
+        fs::create_dir_all(db_name).unwrap();
+        let mut db_params = DBParams::new();
+        db_params.level0_compaction_trigger = 2;
+        db_params.max_bytes_for_level_base = 250;
+        let mut manifest = Manifest::create(db_name, false).unwrap();
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::AddTable(meta(10, 0, b"d", b"f")),
+                VersionEdit::AddTable(meta(4, 1, b"a", b"c")),
+                VersionEdit::AddTable(meta(5, 1, b"e", b"g")),
+                VersionEdit::AddTable(meta(6, 1, b"h", b"k")),
+                VersionEdit::AddTable(meta(1, 2, b"a", b"b")),
+                VersionEdit::AddTable(meta(2, 2, b"i", b"j")),
+            ])
+            .unwrap();
+
+        // level 1 exceeds its size, its table overlapping nothing in level 2 is merged
+        let compaction = Compaction::pick(&manifest, &db_params).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![5]);
+        assert_eq!(compaction.output_level, 2);
+        assert!(compaction.may_drop_delete(b"f"));
+
+        // level 0 reaches the compaction trigger and is merged with the tables of level 1 it overlaps
+        manifest
+            .log_and_apply(vec![VersionEdit::AddTable(meta(11, 0, b"b", b"d"))])
+            .unwrap();
+        db_params.max_bytes_for_level_base = 1000;
+        let compaction = Compaction::pick(&manifest, &db_params).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![11, 10, 4, 5]);
+        assert_eq!(compaction.output_level, 1);
+        assert!(!compaction.may_drop_delete(b"a"));
+        assert!(compaction.may_drop_delete(b"c"));
+
+        db_params.level0_compaction_trigger = 4;
+        assert!(Compaction::pick(&manifest, &db_params).is_none());
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_pick_size_tiered() {
+        let db_name = "target/testcompaction2";
+        fs::create_dir_all(db_name).unwrap();
+        let mut db_params = DBParams::new();
+        db_params.compaction_strategy = CompactionStrategy::SizeTiered;
+        db_params.level0_compaction_trigger = 3;
+        let mut manifest = Manifest::create(db_name, false).unwrap();
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::AddTable(meta(10, 0, b"a", b"m")),
+                VersionEdit::AddTable(meta(11, 0, b"c", b"f")),
+                VersionEdit::AddTable(meta(12, 0, b"b", b"k")),
+                VersionEdit::AddTable(TableMeta {
+                    size: 1000,
+                    ..meta(1, 6, b"a", b"z")
+                }),
+            ])
+            .unwrap();
+
+        // the tables of level 0 are of similar size, they are merged into the level above the larger run
+        let compaction = Compaction::pick(&manifest, &db_params).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![12, 11, 10]);
+        assert_eq!(compaction.output_level, 5);
+        assert!(!compaction.may_drop_delete(b"b"));
+        assert!(compaction.may_drop_delete(b"zz"));
+
+        // without runs of similar size, the newest runs are merged
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::RemoveTable(10),
+                VersionEdit::RemoveTable(11),
+                VersionEdit::RemoveTable(12),
+                VersionEdit::AddTable(TableMeta {
+                    size: 300,
+                    ..meta(13, 5, b"a", b"m")
+                }),
+                VersionEdit::AddTable(meta(14, 0, b"b", b"c")),
+            ])
+            .unwrap();
+        let compaction = Compaction::pick(&manifest, &db_params).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![14, 13]);
+        assert_eq!(compaction.output_level, 5);
+
+        // tables of level 0 are merged with level 1 when it is not empty
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::AddTable(TableMeta {
+                    size: 5000,
+                    ..meta(16, 1, b"a", b"z")
+                }),
+                VersionEdit::AddTable(meta(17, 0, b"x", b"y")),
+            ])
+            .unwrap();
+        let compaction = Compaction::pick(&manifest, &db_params).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![17, 14, 16]);
+        assert_eq!(compaction.output_level, 1);
+
+        db_params.level0_compaction_trigger = 6;
+        assert!(Compaction::pick(&manifest, &db_params).is_none());
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_pick_range() {
+        let db_name = "target/testcompaction3";
+        fs::create_dir_all(db_name).unwrap();
+        let db_params = DBParams::new();
+        let mut manifest = Manifest::create(db_name, false).unwrap();
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::AddTable(meta(10, 0, b"m", b"p")),
+                VersionEdit::AddTable(meta(4, 1, b"a", b"c")),
+                VersionEdit::AddTable(meta(5, 1, b"d", b"k")),
+                VersionEdit::AddTable(meta(6, 1, b"n", b"q")),
+                VersionEdit::AddTable(meta(1, 2, b"a", b"e")),
+                VersionEdit::AddTable(meta(2, 2, b"j", b"l")),
+            ])
+            .unwrap();
+
+        // the range is widened to the tables overlapping the merged tables
+        let compaction =
+            Compaction::pick_range(&manifest, &db_params, Some(b"f"), Some(b"g")).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![4, 5, 1, 2]);
+        assert_eq!(compaction.output_level, db_params.num_levels - 1);
+        assert!(compaction.may_drop_delete(b"b"));
+
+        let compaction = Compaction::pick_range(&manifest, &db_params, Some(b"o"), None).unwrap();
+        assert_eq!(file_nos(&compaction.inputs), vec![10, 6]);
+        assert!(Compaction::pick_range(&manifest, &db_params, Some(b"x"), Some(b"z")).is_none());
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    fn file_nos(tables: &[TableMeta]) -> Vec<u64> {
+        tables.iter().map(|table| table.file_no).collect()
+    }
+
+    fn meta(file_no: u64, level: usize, smallest: &[u8], largest: &[u8]) -> TableMeta {
+        TableMeta {
+            file_no,
+            level,
+            size: 100,
+            smallest: smallest.to_vec(),
+            largest: largest.to_vec(),
+        }
+    }
+}
@@ -1,165 +1,387 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs;
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::Cursor;
+use std::mem;
+use std::ops::RangeBounds;
 use std::path::Path;
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
-use crate::cache::LRUCache;
+use crate::cache::ShardedLRUCache;
+use crate::compaction::{CompactionStats, Compactor};
 use crate::errors::{Error, Result};
+use crate::filter::FilterStats;
+use crate::iterator::{DBIterator, DBReverseIterator};
+use crate::lock;
+use crate::manifest::{self, Manifest, VersionEdit};
 use crate::memtable::{MemTable, MemValue};
-use crate::params::DBParams;
-use crate::table::{Table, TableBuilder};
-
-macro_rules! get_mem_value {
-    ($e:expr) => {
-        match $e {
-            Some(MemValue::Value(value)) => return Ok(Some(value)),
-            Some(MemValue::Delete) => return Ok(None),
-            _ => (),
-        };
-    };
+use crate::params::{DBParams, ReadOptions, SyncPolicy, WriteOptions};
+use crate::repair::{self, RepairReport};
+use crate::snapshot::Snapshot;
+use crate::write_batch::WriteBatch;
+use crate::table::{BlockCache, Table, TableBuilder, TableValue};
+use crate::wal::{self, LogWriter};
+
+// Immutable mem_table waiting to be flushed, along with the number of its write-ahead log
+struct FlushTable {
+    table: Arc<MemTable>,
+    log_no: u64,
+}
+
+// State of the flushes shared by the db and the flush_thread
+struct FlushState {
+    // number of flush_tables that have not been flushed yet
+    pending: usize,
+    // set once a flush has failed, after which no more flush_tables are flushed and writes are refused
+    failed: bool,
 }
 
 pub struct DB {
     /// Name of the db
     db_name: String,
     /// In-memory table for storing key-value pairs.
-    /// After mem_table reaches a specified size it is converted into flush_table
-    mem_table: MemTable,
-    /// Immutable table to be flushed to disk
-    flush_table: Arc<RwLock<Option<MemTable>>>,
+    /// After mem_table reaches a specified size it is converted into a flush_table
+    mem_table: RwLock<Arc<MemTable>>,
+    /// Sequence number of the last write applied to the mem_table
+    last_sequence: AtomicU64,
+    /// Sequence numbers of the live snapshots, along with the number of snapshots taken at each
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+    /// Write-ahead log of the mem_table.
+    /// The lock is held while a write is applied so that the log and the mem_table see writes in the same order
+    log: Arc<Mutex<LogWriter>>,
+    /// Queue of immutable tables to be flushed to disk, from oldest to newest
+    flush_tables: Arc<RwLock<VecDeque<FlushTable>>>,
     /// DBParams object to tune the behaviour of the db
     db_params: DBParams,
-    /// LRU cache that caches the table structures
-    cache: Arc<RwLock<LRUCache<u64, Table>>>,
-    /// Number of log files belonging to the db.
-    /// DB contains log files numbered form 0 to <files-1>
-    files: Arc<AtomicU64>,
-    /// Join-handle of the background flush thread that flushes flush_table to disk
+    /// LRU cache that caches the table structures, along with their open data tables
+    cache: Arc<ShardedLRUCache<u64, Arc<Table>>>,
+    /// Cache of data blocks shared by the log tables, None if disabled
+    block_cache: Option<Arc<BlockCache>>,
+    /// Held by readers from the time they look up the live log tables until they have opened them,
+    /// so that compaction does not remove the files of the tables in between.
+    /// Readers that also lock the log take table_files after it
+    table_files: Arc<RwLock<()>>,
+    /// Number of lookups for which a bloom filter ruled out a log table or let through a log table that
+    /// did not contain the key, shared with the snapshots
+    filter_stats: Arc<FilterStats>,
+    /// Manifest that records the log tables belonging to the db and allocates file numbers
+    manifest: Arc<RwLock<Manifest>>,
+    /// Join-handle of the background flush thread that flushes the flush_tables to disk
     flush_thread_handle: Arc<RwLock<Option<JoinHandle<Result<()>>>>>,
-    /// Condition Variable for synchronizing the flush_thread with the db thread, which waits till the flush_thread has finished flushing before converting the current mem_table to flush_table
-    cv_pair: Arc<(Mutex<bool>, Condvar)>,
+    /// Condition Variable, along with the number of flush_tables that have not been flushed yet and whether a flush
+    /// has failed, for synchronizing the flush_thread with the db thread.
+    /// The db thread only waits for the flush_thread when the queue of flush_tables is full
+    cv_pair: Arc<(Mutex<FlushState>, Condvar)>,
     /// Sender part of the channel that signals the flush_thread that db is closing
     flush_thread_sender: Sender<()>,
+    /// Merges the log tables in the background
+    compactor: Arc<Compactor>,
+    /// Join-handle of the background compaction thread, which compacts the log tables after flushes.
+    /// Only started with DBParams.auto_compaction
+    compaction_thread_handle: Mutex<Option<JoinHandle<Result<()>>>>,
+    /// Sender part of the channel that signals the compaction_thread that a log table was added,
+    /// the thread finishes once all the senders are dropped
+    compaction_thread_sender: Mutex<Option<Sender<()>>>,
+    /// Join-handle of the background sync thread that periodically syncs the log.
+    /// Only started with the SyncPolicy::Periodic policy
+    sync_thread_handle: Mutex<Option<JoinHandle<Result<()>>>>,
+    /// Sender part of the channel that signals the sync_thread that db is closing
+    sync_thread_sender: Option<Sender<()>>,
+    /// LOCK file of the db, the exclusive lock on it is held until the db is closed
+    lock_file: Mutex<Option<File>>,
 }
 
 impl DB {
     /// Opens and loads a database
-    pub fn open(db_name: &str, db_params: DBParams) -> Result<DB> {
-        let num_files = DB::num_log_files(db_name, &db_params)?;
+    pub fn open(db_name: &str, mut db_params: DBParams) -> Result<DB> {
+        let sync = db_params.sync_policy != SyncPolicy::NoSync;
+        let (lock_file, mut manifest) = DB::load_manifest(db_name, &db_params)?;
+        // the db keeps the compaction strategy it was created with, the layout of its levels depends on it
+        match manifest.compaction_strategy() {
+            Some(strategy) => db_params.compaction_strategy = strategy,
+            None => manifest.log_and_apply(vec![VersionEdit::CompactionStrategy(db_params.compaction_strategy)])?,
+        }
+        // the files left behind by a flush or a compaction interrupted by a crash may have file numbers
+        // that were never recorded in the manifest, and that recovery allocates again
+        manifest.remove_obsolete_files()?;
+        DB::recover_logs(db_name, &mut manifest, &db_params)?;
+        manifest.remove_obsolete_files()?;
         let db_name = String::from(db_name);
-        let mem_table = MemTable::new();
-        let flush_table = Arc::new(RwLock::new(None));
-        let cache = Arc::new(RwLock::new(LRUCache::new(db_params.cache_size)));
-        let cv_pair = Arc::new((Mutex::new(false), Condvar::new()));
-        let files = Arc::new(AtomicU64::new(num_files));
+        let mem_table = RwLock::new(Arc::new(MemTable::new()));
+        let log = Arc::new(Mutex::new(LogWriter::create(&db_name, manifest.new_file_no())?));
+        if sync {
+            manifest::sync_dir(&db_name)?;
+        }
+        let flush_tables = Arc::new(RwLock::new(VecDeque::new()));
+        // every cached table holds its data table open
+        let cache = Arc::new(ShardedLRUCache::with_max_len(
+            db_params.cache_size,
+            db_params.max_open_files.max(1),
+        ));
+        let block_cache = if db_params.block_cache_size > 0 {
+            Some(Arc::new(BlockCache::new(db_params.block_cache_size)))
+        } else {
+            None
+        };
+        let cv_pair = Arc::new((
+            Mutex::new(FlushState {
+                pending: 0,
+                failed: false,
+            }),
+            Condvar::new(),
+        ));
+        let manifest = Arc::new(RwLock::new(manifest));
+        let table_files = Arc::new(RwLock::new(()));
+        let compactor = Arc::new(Compactor::new(
+            &db_name,
+            manifest.clone(),
+            cache.clone(),
+            table_files.clone(),
+            db_params.clone(),
+            sync,
+        ));
+        let (compaction_thread_handle, compaction_thread_sender) = if db_params.auto_compaction {
+            let (sender, receiver) = mpsc::channel();
+            let join_handle = DB::start_compaction_thread(receiver, compactor.clone())?;
+            // the tables left by the last session may already need compacting
+            sender.send(())?;
+            (Some(join_handle), Some(sender))
+        } else {
+            (None, None)
+        };
         let (flush_thread_sender, receiver) = mpsc::channel();
         let join_handle = DB::start_flush_thread(
             receiver,
             db_name.clone(),
-            flush_table.clone(),
+            flush_tables.clone(),
             cv_pair.clone(),
-            files.clone(),
+            manifest.clone(),
+            compaction_thread_sender.clone(),
+            db_params.clone(),
         )?;
         let flush_thread_handle = Arc::new(RwLock::new(Some(join_handle)));
+        let (sync_thread_handle, sync_thread_sender) = match db_params.sync_policy {
+            SyncPolicy::Periodic(interval) => {
+                let (sender, receiver) = mpsc::channel();
+                let join_handle = DB::start_sync_thread(receiver, log.clone(), interval)?;
+                (Some(join_handle), Some(sender))
+            }
+            _ => (None, None),
+        };
 
         let db = DB {
             db_name,
             mem_table,
-            flush_table,
+            last_sequence: AtomicU64::new(0),
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            log,
+            flush_tables,
             db_params,
             cache,
-            files,
+            block_cache,
+            table_files,
+            filter_stats: Arc::new(FilterStats::default()),
+            manifest,
             flush_thread_handle,
             cv_pair,
             flush_thread_sender,
+            compactor,
+            compaction_thread_handle: Mutex::new(compaction_thread_handle),
+            compaction_thread_sender: Mutex::new(compaction_thread_sender),
+            sync_thread_handle: Mutex::new(sync_thread_handle),
+            sync_thread_sender,
+            lock_file: Mutex::new(Some(lock_file)),
         };
         Ok(db)
     }
 
-    // returns the number of log_files in the database
-    fn num_log_files(db_name: &str, db_params: &DBParams) -> Result<u64> {
+    /// Repairs a database that cannot be opened because its manifest is missing or damaged.
+    /// The manifest is rebuilt from the log tables found in the directory, write-ahead logs are converted
+    /// to log tables and tables that fail validation are moved to the lost/ subdirectory
+    pub fn repair(db_name: &str) -> Result<RepairReport> {
+        repair::repair(db_name)
+    }
+
+    // Locks the database and loads its manifest, creating a new database if needed.
+    // Returns the LOCK file, which holds the lock until it is closed, along with the manifest
+    fn load_manifest(db_name: &str, db_params: &DBParams) -> Result<(File, Manifest)> {
+        let sync = db_params.sync_policy != SyncPolicy::NoSync;
         let path = Path::new(db_name);
-        let is_path_exists = path.exists();
-        let num_files = if is_path_exists && path.is_dir() {
-            if let Ok(mut file) = File::open(format!("{}/METADATA", db_name)) {
-                let mut buf = [0; 8];
-                file.read_exact(&mut buf)?;
-                Cursor::new(buf).read_u64::<BigEndian>()?
-            } else {
-                return Err(Error::DBNameInvalidError);
-            }
-        } else if is_path_exists {
+        let create = if path.is_dir() {
+            false
+        } else if path.exists() {
             // not a directory
             return Err(Error::DBNameInvalidError);
         } else if db_params.create_if_missing {
             fs::create_dir_all(db_name)?;
-            0
+            true
         } else {
             return Err(Error::DBNameInvalidError);
         };
-        Ok(num_files)
+
+        let lock_file = lock::lock_db(db_name)?;
+        let manifest = if create {
+            Manifest::create(db_name, sync)?
+        } else {
+            Manifest::open(db_name, sync)?
+        };
+        Ok((lock_file, manifest))
+    }
+
+    // replays the write-ahead logs left behind by a crash and flushes their content as a new log table
+    fn recover_logs(db_name: &str, manifest: &mut Manifest, db_params: &DBParams) -> Result<()> {
+        let sync = db_params.sync_policy != SyncPolicy::NoSync;
+        let mut log_files = Vec::new();
+        for entry in fs::read_dir(db_name)? {
+            let file_name = entry?.file_name();
+            if let Some((file_no, "wal")) = manifest::parse_file_name(&file_name.to_string_lossy()) {
+                log_files.push(file_no);
+            }
+        }
+        log_files.sort_unstable();
+
+        // logs older than the manifest's log number have already been flushed
+        let mem_table = MemTable::new();
+        let mut sequence = 0;
+        for &file_no in log_files.iter().filter(|&&file_no| file_no >= manifest.log_no()) {
+            sequence = wal::replay(db_name, file_no, &mem_table, sequence)?;
+        }
+
+        if let Some(&last_log_no) = log_files.last() {
+            manifest.mark_file_no_used(last_log_no);
+            let mut edits = vec![VersionEdit::LogNumber(last_log_no + 1)];
+            if !mem_table.is_empty() {
+                let file_no = manifest.new_file_no();
+                let mut table_builder = TableBuilder::new(db_name, file_no, 0, db_params.bloom_bits_per_key);
+                for (key, value) in mem_table.entries()? {
+                    table_builder.add(&key, &value)?;
+                }
+                edits.push(VersionEdit::AddTable(table_builder.flush(sync)?));
+            }
+            manifest.log_and_apply(edits)?;
+        }
+        Ok(())
     }
 
     fn start_flush_thread(
         receiver: Receiver<()>,
         db_name: String,
-        flush_table: Arc<RwLock<Option<MemTable>>>,
-        cv_pair: Arc<(Mutex<bool>, Condvar)>,
-        db_files: Arc<AtomicU64>,
+        flush_tables: Arc<RwLock<VecDeque<FlushTable>>>,
+        cv_pair: Arc<(Mutex<FlushState>, Condvar)>,
+        manifest: Arc<RwLock<Manifest>>,
+        compaction_sender: Option<Sender<()>>,
+        db_params: DBParams,
     ) -> Result<JoinHandle<Result<()>>> {
-        // background flush thread
+        // background flush thread, flushes the flush_tables from oldest to newest.
+        // After a failed flush, the thread stops flushing but keeps counting off the pending flush_tables, so that
+        // the writers and close never wait for it, and returns the error once the db is closed. The flush_tables
+        // that are left keep their logs for recovery
         let thread_handle = thread::spawn(move || {
-            let files = db_files.load(Ordering::SeqCst);
-            let mut _table_builder = TableBuilder::new(&db_name, files);
+            let (lock, cvar) = &*cv_pair;
+            let mut result = Ok(());
             loop {
-                let &(ref lock, ref cvar) = &*cv_pair;
-                let mut to_flush = lock.lock()?;
-                while !*to_flush {
-                    to_flush = cvar.wait(to_flush)?;
-                }
-
-                match receiver.try_recv() {
-                    Ok(_) | Err(TryRecvError::Disconnected) => {
-                        break;
-                    }
-                    Err(TryRecvError::Empty) => (),
-                };
-
                 {
-                    let table = (flush_table.write()?).take();
-                    if let Some(inner_table) = table {
-                        for (key, value) in inner_table.table.into_iter() {
-                            _table_builder.add(&key, &value)?;
-                        }
-                        _table_builder.flush()?;
-
-                        // update the metadata containing num of files
-                        let mut num_files = Vec::with_capacity(8);
-                        num_files
-                            .write_u64::<BigEndian>(_table_builder.file_no())
-                            .unwrap();
-                        File::create(format!("{}/METADATA", db_name))
-                            .unwrap()
-                            .write_all(&num_files)
-                            .unwrap();
-
-                        // update num_files property of db
-                        db_files.fetch_add(1, Ordering::SeqCst);
+                    let mut state = lock.lock()?;
+                    while state.pending == 0 {
+                        match receiver.try_recv() {
+                            Ok(_) | Err(TryRecvError::Disconnected) => {
+                                return result;
+                            }
+                            Err(TryRecvError::Empty) => (),
+                        };
+                        state = cvar.wait(state)?;
                     }
                 }
 
-                *to_flush = false;
-                cvar.notify_one();
+                if result.is_ok() {
+                    result = DB::flush_oldest(&db_name, &flush_tables, &manifest, compaction_sender.as_ref(), &db_params);
+                }
+
+                // wake up the writers stalled on a full queue and close
+                let mut state = lock.lock()?;
+                state.pending -= 1;
+                state.failed |= result.is_err();
+                cvar.notify_all();
             }
+        });
+        Ok(thread_handle)
+    }
+
+    // flushes the oldest flush_table to a new log table of level 0 and adds the table to the db
+    fn flush_oldest(
+        db_name: &str,
+        flush_tables: &RwLock<VecDeque<FlushTable>>,
+        manifest: &RwLock<Manifest>,
+        compaction_sender: Option<&Sender<()>>,
+        db_params: &DBParams,
+    ) -> Result<()> {
+        // the flush_table stays visible to readers until its log table has been added to the db
+        let (entries, log_no) = match (flush_tables.read()?).front() {
+            Some(table) => (table.table.entries()?, table.log_no),
+            None => return Ok(()),
+        };
+        let file_no = (manifest.write()?).new_file_no();
+        let mut table_builder = TableBuilder::new(db_name, file_no, 0, db_params.bloom_bits_per_key);
+        for (key, value) in entries {
+            table_builder.add(&key, &value)?;
+        }
+        let meta = table_builder.flush(db_params.sync_policy != SyncPolicy::NoSync)?;
+
+        // add the log table to the db
+        (manifest.write()?).log_and_apply(vec![
+            VersionEdit::AddTable(meta),
+            VersionEdit::LogNumber(log_no + 1),
+        ])?;
+        (flush_tables.write()?).pop_front();
+
+        // the write-ahead log of the flushed table is no longer needed
+        fs::remove_file(wal::log_file_name(db_name, log_no))?;
+
+        // the compaction thread keeps receiving after a failed compaction, and a failed send
+        // must not stop the flushes the writers are waiting for
+        if let Some(sender) = compaction_sender {
+            let _ = sender.send(());
+        }
+        Ok(())
+    }
+
+    fn start_compaction_thread(
+        receiver: Receiver<()>,
+        compactor: Arc<Compactor>,
+    ) -> Result<JoinHandle<Result<()>>> {
+        // background compaction thread, compacts the log tables every time a table is added until
+        // the db no longer needs compacting
+        // After a failed compaction, the thread stops compacting but keeps receiving until the db is closed,
+        // which gets the error
+        let thread_handle = thread::spawn(move || {
+            let mut result = Ok(());
+            while receiver.recv().is_ok() {
+                // the signals received while compacting are covered by the next compaction
+                while receiver.try_recv().is_ok() {}
+                if result.is_ok() {
+                    result = compactor.compact_while_needed();
+                }
+            }
+            result
+        });
+        Ok(thread_handle)
+    }
 
+    fn start_sync_thread(
+        receiver: Receiver<()>,
+        log: Arc<Mutex<LogWriter>>,
+        interval: Duration,
+    ) -> Result<JoinHandle<Result<()>>> {
+        // background sync thread, the writes of all writers since the last sync are synced together
+        let thread_handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                (log.lock()?).sync()?;
+            }
             Ok(())
         });
         Ok(thread_handle)
@@ -167,46 +389,298 @@ impl DB {
 
     /// Returns the value corresponding to the key
     pub fn get<S: AsRef<[u8]>>(&self, key: S) -> Result<Option<Vec<u8>>> {
+        self.get_with_options(key, &ReadOptions::new())
+    }
+
+    /// Returns the value corresponding to the key using the given read options
+    pub fn get_with_options<S: AsRef<[u8]>>(
+        &self,
+        key: S,
+        read_options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>> {
         let key_bytes = key.as_ref();
-        get_mem_value!(self.mem_table.get(&key_bytes));
+        let verify_checksums = read_options.verify_checksums;
+        let mem_table = (self.mem_table.read()?).clone();
+        get_mem_value!(mem_table.get(key_bytes, u64::MAX)?);
 
         {
-            let guard = self.flush_table.read()?;
-            if let Some(ref table) = *guard {
-                get_mem_value!(table.get(&key_bytes));
+            // probe the flush_tables from newest to oldest
+            let guard = self.flush_tables.read()?;
+            for table in guard.iter().rev() {
+                get_mem_value!(table.table.get(key_bytes, u64::MAX)?);
             }
         }
 
-        let num_files = self.files.load(Ordering::SeqCst);
-        for i in 0..num_files {
-            let mut guard = self.cache.write()?;
-            if let Some(table) = guard.get(&(num_files - i - 1)) {
-                let val = table.get(&key_bytes)?;
-                get_mem_value!(val);
-            } else {
-                let table = Table::open(&self.db_name, num_files - i - 1)?;
-                let val = table.get(&key_bytes)?;
-                guard.put(num_files - i - 1, table);
-                get_mem_value!(val);
-            };
+        // probe the log tables that may contain the key from newest to oldest
+        let _table_files = self.table_files.read()?;
+        let tables = (self.manifest.read()?).tables_for_key(key_bytes);
+        for file_no in tables {
+            let table = self.table(file_no, verify_checksums)?;
+            // a block of the table is only read if its filter may contain the key
+            let value = table.get_filtered(key_bytes, verify_checksums, &self.filter_stats)?;
+            get_mem_value!(value.map(TableValue::into_mem_value));
         }
         Ok(None)
     }
 
+    // Returns the log table from the cache, loading it if needed.
+    // The table is opened without holding any lock of the cache, so two threads missing the same table
+    // may both open it, and the last one to finish keeps its copy in the cache
+    fn table(&self, file_no: u64, verify_checksums: bool) -> Result<Arc<Table>> {
+        if let Some(table) = self.cache.get(&file_no)? {
+            return Ok(table);
+        }
+        let table = Arc::new(Table::open(
+            &self.db_name,
+            file_no,
+            self.block_cache.clone(),
+            self.db_params.mmap_reads,
+            verify_checksums,
+        )?);
+        self.cache.put_with_charge(file_no, table.clone(), table.memory_usage())?;
+        Ok(table)
+    }
+
+    /// Returns a snapshot of the db, which sees the db exactly as it is now until it is dropped
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        self.take_snapshot(ReadOptions::new().verify_checksums)
+    }
+
+    fn take_snapshot(&self, verify_checksums: bool) -> Result<Snapshot> {
+        // holding the log lock keeps out the writes and the switch of the mem_table while the state is captured,
+        // so that the log tables only contain writes that are visible to the snapshot.
+        // table_files is only taken once the log lock is held, so a reader never holds it while waiting for a writer
+        let log = self.log.lock()?;
+        let table_files = self.table_files.read()?;
+        let (mut snapshot, metas) = {
+            let mut mem_tables = vec![(self.mem_table.read()?).clone()];
+            for table in (self.flush_tables.read()?).iter().rev() {
+                mem_tables.push(table.table.clone());
+            }
+            let metas = (self.manifest.read()?).table_metas();
+            let sequence = self.last_sequence.load(Ordering::SeqCst);
+            let snapshot = Snapshot::new(
+                sequence,
+                mem_tables,
+                self.snapshots.clone(),
+                self.filter_stats.clone(),
+            )?;
+            (snapshot, metas)
+        };
+        drop(log);
+
+        let mut tables = Vec::with_capacity(metas.len());
+        for meta in metas {
+            let table = self.table(meta.file_no, verify_checksums)?;
+            tables.push((meta, table));
+        }
+        drop(table_files);
+        snapshot.pin_tables(tables);
+        Ok(snapshot)
+    }
+
+    /// Returns an iterator over all the key-value pairs of the db in increasing order of keys
+    pub fn iter(&self) -> Result<DBIterator> {
+        self.iter_with_options(&ReadOptions::new())
+    }
+
+    /// Returns an iterator over all the key-value pairs of the db in increasing order of keys
+    /// using the given read options
+    pub fn iter_with_options(&self, read_options: &ReadOptions) -> Result<DBIterator> {
+        self.take_snapshot(read_options.verify_checksums)?
+            .iter_with_options(read_options)
+    }
+
+    /// Returns an iterator over the key-value pairs of the db with keys within the range, in increasing order of keys
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Result<DBIterator> {
+        self.range_with_options(range, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key-value pairs of the db with keys within the range, in increasing order of keys
+    /// using the given read options
+    pub fn range_with_options<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        read_options: &ReadOptions,
+    ) -> Result<DBIterator> {
+        self.take_snapshot(read_options.verify_checksums)?
+            .range_with_options(range, read_options)
+    }
+
+    /// Returns an iterator over the key-value pairs of the db with keys within the range, in decreasing order of keys
+    pub fn range_rev<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Result<DBReverseIterator> {
+        self.range_rev_with_options(range, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key-value pairs of the db with keys within the range, in decreasing order of keys
+    /// using the given read options
+    pub fn range_rev_with_options<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        read_options: &ReadOptions,
+    ) -> Result<DBReverseIterator> {
+        self.take_snapshot(read_options.verify_checksums)?
+            .range_rev_with_options(range, read_options)
+    }
+
+    /// Returns an iterator over the key-value pairs of the db with keys starting with prefix, in increasing order of keys
+    pub fn prefix_iter<K: AsRef<[u8]>>(&self, prefix: K) -> Result<DBIterator> {
+        self.prefix_iter_with_options(prefix, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key-value pairs of the db with keys starting with prefix, in increasing order of keys
+    /// using the given read options
+    pub fn prefix_iter_with_options<K: AsRef<[u8]>>(
+        &self,
+        prefix: K,
+        read_options: &ReadOptions,
+    ) -> Result<DBIterator> {
+        self.take_snapshot(read_options.verify_checksums)?
+            .prefix_iter_with_options(prefix, read_options)
+    }
+
+    /// Compacts the log tables holding keys from start to end, inclusive, None meaning no bound on that side.
+    /// The mem_table is flushed first, then all the log tables overlapping the range are merged into new tables,
+    /// dropping the overwritten values and the deleted keys. Returns once the merged tables have been removed,
+    /// along with the sizes read, written and reclaimed
+    pub fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<CompactionStats> {
+        self.start_flushing()?;
+        self.wait_for_flushes()?;
+        self.compactor.compact_range(start, end)
+    }
+
+    /// Returns the value of a property of the db, or None if the property is unknown.
+    /// Properties:
+    /// - `indydb.cur-size-active-mem-table` approximate size in bytes of the mem_table
+    /// - `indydb.size-all-mem-tables` approximate size in bytes of the mem_table and the flush_tables
+    /// - `indydb.num-entries-active-mem-table` number of entries in the mem_table
+    /// - `indydb.num-immutable-mem-table` number of flush_tables waiting to be flushed
+    /// - `indydb.num-snapshots` number of live snapshots
+    /// - `indydb.bloom-filter-hits` number of times a bloom filter ruled out a log table during a lookup
+    /// - `indydb.bloom-filter-misses` number of times a bloom filter let through a log table that did not contain the key
+    /// - `indydb.table-cache-usage` size in bytes of the indexes and filters of the tables in the cache of log tables
+    /// - `indydb.block-cache-usage` size in bytes of the blocks in the block cache
+    /// - `indydb.block-cache-hits` number of block reads served from the block cache
+    /// - `indydb.block-cache-misses` number of block reads that missed the block cache
+    /// - `indydb.num-log-tables` number of live log tables
+    /// - `indydb.num-log-tables-at-level<N>` number of live log tables in level N
+    /// - `indydb.level-size-at-level<N>` size in bytes of the live log tables in level N
+    /// - `indydb.num-compactions` number of compactions run since the db was opened
+    pub fn get_property(&self, name: &str) -> Result<Option<String>> {
+        let mem_table = (self.mem_table.read()?).clone();
+        let value = match name {
+            "indydb.cur-size-active-mem-table" => mem_table.memory_usage(),
+            "indydb.size-all-mem-tables" => {
+                let flush_tables = self.flush_tables.read()?;
+                mem_table.memory_usage()
+                    + flush_tables
+                        .iter()
+                        .map(|table| table.table.memory_usage())
+                        .sum::<usize>()
+            }
+            "indydb.num-entries-active-mem-table" => mem_table.len(),
+            "indydb.num-immutable-mem-table" => (self.flush_tables.read()?).len(),
+            "indydb.num-snapshots" => (self.snapshots.lock()?).values().sum(),
+            "indydb.bloom-filter-hits" => self.filter_stats.hits.load(Ordering::Relaxed),
+            "indydb.bloom-filter-misses" => self.filter_stats.misses.load(Ordering::Relaxed),
+            "indydb.table-cache-usage" => self.cache.usage()?,
+            "indydb.block-cache-usage" => match self.block_cache {
+                Some(ref block_cache) => block_cache.usage()?,
+                None => 0,
+            },
+            "indydb.block-cache-hits" => self.block_cache.as_ref().map_or(0, |cache| cache.hits()),
+            "indydb.block-cache-misses" => self.block_cache.as_ref().map_or(0, |cache| cache.misses()),
+            "indydb.num-log-tables" => (self.manifest.read()?).tables().len(),
+            "indydb.num-compactions" => self.compactor.num_compactions(),
+            _ => {
+                let (property, level) = match name.find(|c: char| c.is_ascii_digit()) {
+                    Some(i) => name.split_at(i),
+                    None => return Ok(None),
+                };
+                let level = match level.parse::<usize>() {
+                    Ok(level) => level,
+                    Err(_) => return Ok(None),
+                };
+                let manifest = self.manifest.read()?;
+                match property {
+                    "indydb.num-log-tables-at-level" => manifest.level(level).len(),
+                    "indydb.level-size-at-level" => {
+                        manifest.level(level).iter().map(|table| table.size).sum::<u64>() as usize
+                    }
+                    _ => return Ok(None),
+                }
+            }
+        };
+        Ok(Some(value.to_string()))
+    }
+
     /// Insertes a key-value pair to the database.
     /// If key was already present the value is updated.
     pub fn put<S: AsRef<[u8]>>(&self, key: S, value: S) -> Result<()> {
-        self.mem_table.put(key.as_ref(), value.as_ref())?;
-        if self.mem_table.size() >= self.db_params.write_buffer_size {
-            self.start_flushing()?;
-        }
-        Ok(())
+        self.put_with_options(key, value, &WriteOptions::new())
+    }
+
+    /// Insertes a key-value pair to the database using the given write options
+    pub fn put_with_options<S: AsRef<[u8]>>(
+        &self,
+        key: S,
+        value: S,
+        write_options: &WriteOptions,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write_with_options(&batch, write_options)
     }
 
     /// Deletes a key from the database
     pub fn delete<S: AsRef<[u8]>>(&self, key: S) -> Result<()> {
-        self.mem_table.delete(key.as_ref())?;
-        if self.mem_table.size() >= self.db_params.write_buffer_size {
+        self.delete_with_options(key, &WriteOptions::new())
+    }
+
+    /// Deletes a key from the database using the given write options
+    pub fn delete_with_options<S: AsRef<[u8]>>(
+        &self,
+        key: S,
+        write_options: &WriteOptions,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write_with_options(&batch, write_options)
+    }
+
+    /// Applies the puts and deletes of the batch atomically
+    pub fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.write_with_options(batch, &WriteOptions::new())
+    }
+
+    /// Applies the puts and deletes of the batch atomically using the given write options.
+    /// The batch is appended to the write-ahead log as a single record and then applied to the mem_table
+    pub fn write_with_options(&self, batch: &WriteBatch, write_options: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // the mem_tables can no longer be flushed, the error is returned by close
+        if (self.cv_pair.0.lock()?).failed {
+            return Err(Error::BackgroundFlushError);
+        }
+        {
+            let mut log = self.log.lock()?;
+            let sync = write_options.sync
+                || match self.db_params.sync_policy {
+                    SyncPolicy::EveryWrite => true,
+                    SyncPolicy::Batch(writes) => log.unsynced() + 1 >= writes,
+                    SyncPolicy::NoSync | SyncPolicy::Periodic(_) => false,
+                };
+            log.add_record(batch.ops(), sync)?;
+
+            // the versions replaced by the batch are kept while the latest snapshot may read them
+            let latest_snapshot = (self.snapshots.lock()?).keys().next_back().copied();
+            let sequence = self.last_sequence.load(Ordering::SeqCst);
+            (self.mem_table.read()?).write(batch.ops(), sequence + 1, latest_snapshot)?;
+            self.last_sequence
+                .store(sequence + batch.len() as u64, Ordering::SeqCst);
+        }
+        if (self.mem_table.read()?).memory_usage() >= self.db_params.write_buffer_size {
             self.start_flushing()?;
         }
         Ok(())
@@ -220,64 +694,128 @@ impl DB {
             return Ok(());
         }
 
-        // flush the mem_table since db is closing
+        // flush the mem_table since db is closing, the error of a failed flush is returned by the flush thread
         self.start_flushing()?;
-
-        {
-            let &(ref lock, ref cvar) = &*self.cv_pair;
-            let mut to_flush = lock.lock()?;
-            while *to_flush {
-                to_flush = cvar.wait(to_flush)?;
-            }
+        match self.wait_for_flushes() {
+            Ok(()) | Err(Error::BackgroundFlushError) => (),
+            Err(e) => return Err(e),
         }
 
         // signal the background thread to finish and close
         self.flush_thread_sender.send(())?;
 
         {
-            let &(ref lock, ref cvar) = &*self.cv_pair;
-            let mut to_flush = lock.lock().unwrap();
-            *to_flush = true;
-            cvar.notify_one();
+            let (lock, cvar) = &*self.cv_pair;
+            let _state = lock.lock()?;
+            cvar.notify_all();
         }
 
         // join the flush_thread_handle
         let join_handle = (self.flush_thread_handle.write()?).take();
-        if join_handle.unwrap().join().is_err() {
+        let flush_result = join_handle.unwrap().join().unwrap_or(Err(Error::BackgroundFlushError));
+
+        // stop the compaction thread, if any, once it has finished the compaction in progress
+        (self.compaction_thread_sender.lock()?).take();
+        let compaction_result = match (self.compaction_thread_handle.lock()?).take() {
+            Some(join_handle) => join_handle.join().unwrap_or(Err(Error::BackgroundCompactionError)),
+            None => Ok(()),
+        };
+
+        // stop the sync thread, if any, which has already finished if a sync failed
+        let sync_result = match (self.sync_thread_handle.lock()?).take() {
+            Some(join_handle) => {
+                if let Some(ref sender) = self.sync_thread_sender {
+                    let _ = sender.send(());
+                }
+                join_handle.join().unwrap_or(Err(Error::BackgroundFlushError))
+            }
+            None => Ok(()),
+        };
+
+        // the mem_table has been flushed so its (empty) log can be removed
+        if flush_result.is_ok() {
+            let log = self.log.lock()?;
+            fs::remove_file(wal::log_file_name(&self.db_name, log.file_no()))?;
+        }
+
+        // release the lock on the db, which is left as it is on disk after an error of the background threads
+        (self.lock_file.lock()?).take();
+        flush_result?;
+        compaction_result?;
+        sync_result
+    }
+
+    // simulates a crash of the process: the mem_table is not flushed and the background threads are leaked,
+    // only the lock on the db is released so that it can be opened again.
+    // The flushes and the compaction already in progress are waited for, and compactions are stopped,
+    // since the leaked threads would otherwise race with the next open of the db
+    #[cfg(test)]
+    pub(crate) fn crash(self) {
+        {
+            let (lock, cvar) = &*self.cv_pair;
+            let mut state = lock.lock().unwrap();
+            while state.pending > 0 {
+                state = cvar.wait(state).unwrap();
+            }
+        }
+        std::mem::forget(self.compactor.stop());
+        (self.lock_file.lock().unwrap()).take();
+        std::mem::forget(self);
+    }
+
+    // waits till all the flush_tables have been flushed, fails if a flush has failed
+    fn wait_for_flushes(&self) -> Result<()> {
+        let (lock, cvar) = &*self.cv_pair;
+        let mut state = lock.lock()?;
+        while state.pending > 0 {
+            state = cvar.wait(state)?;
+        }
+        if state.failed {
             return Err(Error::BackgroundFlushError);
         }
         Ok(())
     }
 
-    // converts the mem_table to flush_table and signals the background flush_thread to start flushing
+    // converts the mem_table to a flush_table and signals the background flush_thread to start flushing
     fn start_flushing(&self) -> Result<()> {
-        if self.mem_table.is_empty() {
+        // too many tables in level 0 slow down reads, so the writer waits for them to be compacted before it
+        // switches the mem_table, without holding the log lock that the other writers and the readers wait for
+        if self.db_params.auto_compaction && !(self.mem_table.read()?).is_empty() {
+            self.compactor.wait_for_level0()?;
+        }
+
+        // holding the log lock keeps writers out while the mem_table and its log are switched
+        let mut log = self.log.lock()?;
+        if (self.mem_table.read()?).is_empty() {
             return Ok(());
         }
 
-        // wait if the flush thread has not finished flushing the last flush_table
-        {
-            let &(ref lock, ref cvar) = &*self.cv_pair;
-            let mut to_flush = lock.lock()?;
-            while *to_flush {
-                to_flush = cvar.wait(to_flush)?; // writer blocks!! TODO: think of a better solution
-            }
+        let (lock, cvar) = &*self.cv_pair;
+        let mut state = lock.lock()?;
+        // writers only stall when the queue of flush_tables is full
+        while state.pending >= self.db_params.max_flush_tables.max(1) {
+            state = cvar.wait(state)?;
         }
 
-        // replace the memtable with a new one and convert it to the flush_table
-        {
-            let mut w_guard = self.flush_table.write()?;
-            assert!(w_guard.is_none(), "flush_table is not none");
-            *w_guard = Some(self.mem_table.clear());
+        // replace the memtable with a new one and add it to the queue of flush_tables
+        (self.flush_tables.write()?).push_back(FlushTable {
+            table: mem::replace(&mut *self.mem_table.write()?, Arc::new(MemTable::new())),
+            log_no: log.file_no(),
+        });
+
+        // the flush_table keeps its log until it is flushed, the new mem_table gets a fresh one
+        if self.db_params.sync_policy != SyncPolicy::NoSync {
+            log.sync()?;
+        }
+        let log_no = (self.manifest.write()?).new_file_no();
+        *log = LogWriter::create(&self.db_name, log_no)?;
+        if self.db_params.sync_policy != SyncPolicy::NoSync {
+            manifest::sync_dir(&self.db_name)?;
         }
 
         // signal the flush_thread to start flushing
-        {
-            let &(ref lock, ref cvar) = &*self.cv_pair;
-            let mut to_flush = lock.lock().unwrap();
-            *to_flush = true;
-            cvar.notify_one();
-        }
+        state.pending += 1;
+        cvar.notify_all();
         Ok(())
     }
 }
@@ -7,12 +7,39 @@ use std::sync::PoisonError;
 pub enum Error {
     IOError(io::Error),
     DBCorruptionError,
+    /// A record of a log table is corrupted.
+    /// Contains the number of the log table, the file the record belongs to ("ix" or "dt"),
+    /// the offset of the record in the file and the kind of corruption
+    TableCorruptionError {
+        file_no: u64,
+        file_type: &'static str,
+        offset: u64,
+        kind: Corruption,
+    },
+    /// The db was written in an on-disk format that this version cannot read
+    IncompatibleFormatError,
     BackgroundFlushError,
+    BackgroundCompactionError,
     DBNameInvalidError,
+    /// The db is already opened by another DB object, in this or another process
+    DBLockedError,
     SyncPoisonError,
     SendError,
 }
 
+/// Kind of corruption found in a record of a log table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Corruption {
+    /// the record extends past the end of the file
+    Truncated,
+    /// a length or an offset in the record points outside of the file
+    BadLength,
+    /// the record has an unknown value marker
+    BadMarker,
+    /// the checksum of the record does not match its content
+    ChecksumMismatch,
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::IOError(err)
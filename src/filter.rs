@@ -0,0 +1,176 @@
+use byteorder::{BigEndian, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::mem;
+use std::sync::atomic::AtomicUsize;
+
+use crate::errors::{Corruption, Error, Result};
+
+// Bloom filter over the keys of a log table, stored next to it in the filter file (*N*.bf) as
+// <number of probes><filter bits><checksum>
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_probes: u32,
+}
+
+// Outcomes of the lookups of log tables that checked the bloom filter of the table
+#[derive(Default)]
+pub struct FilterStats {
+    // lookups for which the filter ruled out the table
+    pub hits: AtomicUsize,
+    // lookups for which the filter let through a table that did not contain the key
+    pub misses: AtomicUsize,
+}
+
+impl BloomFilter {
+    // builds a filter with bits_per_key bits for each of the hashed keys
+    pub fn build(key_hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        // the false positive rate is the lowest with bits_per_key * ln(2) probes
+        let num_probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        // tiny filters have a very high false positive rate, so at least 64 bits are used
+        let num_bits = (key_hashes.len() * bits_per_key).max(64);
+        let mut bits = vec![0; num_bits.div_ceil(8)];
+        let num_bits = bits.len() as u64 * 8;
+        for &hash in key_hashes {
+            for bit in probes(hash, num_probes, num_bits) {
+                bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        BloomFilter { bits, num_probes }
+    }
+
+    // memory in bytes taken up by the filter
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<BloomFilter>() + self.bits.capacity()
+    }
+
+    // returns false if the key is definitely not in the table
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let num_bits = self.bits.len() as u64 * 8;
+        probes(hash(key), self.num_probes, num_bits)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+
+    // writes the filter of the table to its filter file
+    pub fn write(&self, db_name: &str, file_no: u64, sync: bool) -> Result<()> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 5);
+        buf.push(self.num_probes as u8);
+        buf.extend_from_slice(&self.bits);
+        buf.write_u32::<BigEndian>(crc32c::crc32c(&buf))?;
+        let mut file = File::create(filter_file_name(db_name, file_no))?;
+        file.write_all(&buf)?;
+        if sync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // reads the filter of the table, if the table has one
+    pub fn read(db_name: &str, file_no: u64) -> Result<Option<BloomFilter>> {
+        let buf = match fs::read(filter_file_name(db_name, file_no)) {
+            Ok(buf) => buf,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let error = |kind| Error::TableCorruptionError {
+            file_no,
+            file_type: "bf",
+            offset: 0,
+            kind,
+        };
+        if buf.len() < 5 {
+            return Err(error(Corruption::Truncated));
+        }
+        let (filter, checksum) = buf.split_at(buf.len() - 4);
+        if crc32c::crc32c(filter) != u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+            return Err(error(Corruption::ChecksumMismatch));
+        }
+        // lookups probe the bits modulo their number, a filter always has some
+        if filter.len() < 2 {
+            return Err(error(Corruption::BadLength));
+        }
+        Ok(Some(BloomFilter {
+            bits: filter[1..].to_vec(),
+            num_probes: u32::from(filter[0]),
+        }))
+    }
+}
+
+// 64-bit FNV-1a hash of the key
+pub fn hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// bits probed for a key, derived from the two halves of its hash by double hashing
+fn probes(hash: u64, num_probes: u32, num_bits: u64) -> impl Iterator<Item = u64> {
+    let h1 = hash & 0xffff_ffff;
+    let h2 = (hash >> 32) | 1;
+    (0..u64::from(num_probes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+pub fn filter_file_name(db_name: &str, file_no: u64) -> String {
+    format!("{}/{}.bf", db_name, file_no)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, BloomFilter};
+    use crate::errors::{Corruption, Error};
+    use std::fs;
+
+    #[test]
+    fn test_false_positive_rate() {
+        let keys: Vec<_> = (0..10000).map(|i| format!("key{}", i)).collect();
+        let hashes: Vec<_> = keys.iter().map(|key| hash(key.as_bytes())).collect();
+        let filter = BloomFilter::build(&hashes, 10);
+        assert!(keys.iter().all(|key| filter.may_contain(key.as_bytes())));
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("missing{}", i).as_bytes()))
+            .count();
+        // about 1% with 10 bits per key
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_filter_file() {
+        let db_name = "target/testfilter1";
+        fs::create_dir_all(db_name).unwrap();
+        let filter = BloomFilter::build(&[hash(b"key")], 10);
+        filter.write(db_name, 1, false).unwrap();
+        let filter = BloomFilter::read(db_name, 1).unwrap().unwrap();
+        assert!(filter.may_contain(b"key"));
+        assert!(BloomFilter::read(db_name, 2).unwrap().is_none());
+
+        let file_name = format!("{}/1.bf", db_name);
+        let mut buf = fs::read(&file_name).unwrap();
+        buf[1] ^= 1;
+        fs::write(&file_name, buf).unwrap();
+        match BloomFilter::read(db_name, 1) {
+            Err(Error::TableCorruptionError {
+                kind: Corruption::ChecksumMismatch,
+                ..
+            }) => (),
+            _ => panic!("corruption not detected"),
+        }
+
+        // a filter without any bits, even with a valid checksum
+        let mut buf = vec![7];
+        buf.extend_from_slice(&crc32c::crc32c(&buf).to_be_bytes());
+        fs::write(&file_name, buf).unwrap();
+        match BloomFilter::read(db_name, 1) {
+            Err(Error::TableCorruptionError {
+                kind: Corruption::BadLength,
+                ..
+            }) => (),
+            _ => panic!("empty filter not detected"),
+        }
+        fs::remove_dir_all(db_name).unwrap();
+    }
+}
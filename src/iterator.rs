@@ -0,0 +1,551 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::errors::Result;
+use crate::memtable::MemValue;
+use crate::table::{Block, Table};
+
+// Sorted entries of a mem_table or a log table merged by the iterator
+pub enum Source {
+    // copy of the entries of a mem_table within the bounds of the iterator
+    Mem(Vec<(Vec<u8>, MemValue)>),
+    Table(Arc<Table>),
+}
+
+// position that a cursor moves to when it is next used
+enum Target {
+    // before the first entry after the bound
+    First(Bound<Vec<u8>>),
+    // after the last entry before the bound
+    Last(Bound<Vec<u8>>),
+}
+
+// Position of the iterator in one of its sources, entries before pos have keys less than the entries after it.
+// The entries of a log table are read one block at a time, so the position of the cursor is within the block
+// that was last read, and the block is only switched once the iterator moves past its first or last entry
+struct Cursor {
+    // log table the entries are read from, None for a mem_table
+    table: Option<Arc<Table>>,
+    // index of the block of the table held in entries
+    block: usize,
+    // entries of the mem_table or of the current block of the table
+    entries: Arc<Block>,
+    pos: usize,
+    // seeks are applied lazily, so that seeking never reads a block that is not iterated over
+    target: Option<Target>,
+}
+
+impl Cursor {
+    fn new(source: Source, start: &Bound<Vec<u8>>) -> Cursor {
+        let (table, entries) = match source {
+            Source::Mem(entries) => (None, Arc::new(entries)),
+            Source::Table(table) => (Some(table), Arc::new(Vec::new())),
+        };
+        Cursor {
+            table,
+            block: 0,
+            entries,
+            pos: 0,
+            target: Some(Target::First(start.clone())),
+        }
+    }
+
+    // applies the pending seek, if any, reading the block of the table that contains the target
+    fn seek_target(&mut self, verify_checksums: bool) -> Result<()> {
+        let target = match self.target.take() {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let bound = match target {
+            Target::First(ref bound) | Target::Last(ref bound) => bound.as_ref().map(Vec::as_slice),
+        };
+        if let Some(ref table) = self.table {
+            if table.num_blocks() == 0 {
+                return Ok(());
+            }
+            self.block = match (&target, bound) {
+                (_, Bound::Included(key)) | (_, Bound::Excluded(key)) => table.find_block(key),
+                (Target::First(_), Bound::Unbounded) => 0,
+                (Target::Last(_), Bound::Unbounded) => table.num_blocks() - 1,
+            };
+            self.entries = table.read_block(self.block, verify_checksums)?;
+        }
+        self.pos = match target {
+            Target::First(_) => first_position(&self.entries, bound),
+            Target::Last(_) => last_position(&self.entries, bound),
+        };
+        Ok(())
+    }
+
+    // makes sure that the entry after the position, if any, is in the current block
+    fn prepare_next(&mut self, verify_checksums: bool) -> Result<()> {
+        self.seek_target(verify_checksums)?;
+        if let Some(ref table) = self.table {
+            while self.pos == self.entries.len() && self.block + 1 < table.num_blocks() {
+                self.block += 1;
+                self.entries = table.read_block(self.block, verify_checksums)?;
+                self.pos = 0;
+            }
+        }
+        Ok(())
+    }
+
+    // makes sure that the entry before the position, if any, is in the current block
+    fn prepare_prev(&mut self, verify_checksums: bool) -> Result<()> {
+        self.seek_target(verify_checksums)?;
+        if let Some(ref table) = self.table {
+            while self.pos == 0 && self.block > 0 {
+                self.block -= 1;
+                self.entries = table.read_block(self.block, verify_checksums)?;
+                self.pos = self.entries.len();
+            }
+        }
+        Ok(())
+    }
+
+    // key of the entry after the position
+    fn key(&self) -> Option<&[u8]> {
+        self.entries.get(self.pos).map(|(key, _)| key.as_slice())
+    }
+
+    // key of the entry before the position
+    fn prev_key(&self) -> Option<&[u8]> {
+        if self.pos > 0 {
+            Some(&self.entries[self.pos - 1].0)
+        } else {
+            None
+        }
+    }
+}
+
+/// Iterator over the key-value pairs of the db in increasing order of keys.
+/// The mem_table, the flush_tables and the log tables are merged so that the newest value of a key wins,
+/// and deleted keys are skipped.
+/// The iterator is positioned between two keys: `next` returns the entry after the position and `prev` the entry
+/// before it, moving the position past the returned entry
+pub struct DBIterator {
+    // cursors ordered from the newest source to the oldest
+    cursors: Vec<Cursor>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    verify_checksums: bool,
+}
+
+impl DBIterator {
+    // creates an iterator over the entries of the sources, which are ordered from newest to oldest,
+    // with keys within the bounds
+    pub(crate) fn new(
+        sources: Vec<Source>,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        verify_checksums: bool,
+    ) -> DBIterator {
+        let cursors = sources
+            .into_iter()
+            .map(|source| Cursor::new(source, &start))
+            .collect();
+        DBIterator {
+            cursors,
+            start,
+            end,
+            verify_checksums,
+        }
+    }
+
+    /// Positions the iterator at the first key that is not less than key, jumping directly to
+    /// the block of every table that may contain it.
+    /// Keys outside the range of the iterator are never returned, so seeking before the range positions
+    /// the iterator at its start and seeking after the range at its end
+    pub fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = key.as_ref();
+        let target = if !self.after_start(key) {
+            self.start.clone()
+        } else if !self.before_end(key) {
+            other_side(&self.end)
+        } else {
+            Bound::Included(key.to_vec())
+        };
+        for cursor in self.cursors.iter_mut() {
+            cursor.target = Some(Target::First(target.clone()));
+        }
+    }
+
+    /// Positions the iterator after the last key that is not greater than key, so that `prev` returns it.
+    /// Keys outside the range of the iterator are never returned, so seeking after the range positions
+    /// the iterator at its end and seeking before the range at its start
+    pub fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = key.as_ref();
+        let target = if !self.before_end(key) {
+            self.end.clone()
+        } else if !self.after_start(key) {
+            other_side(&self.start)
+        } else {
+            Bound::Included(key.to_vec())
+        };
+        for cursor in self.cursors.iter_mut() {
+            cursor.target = Some(Target::Last(target.clone()));
+        }
+    }
+
+    /// Positions the iterator at the end of its range, so that `prev` returns the last key
+    pub fn seek_to_last(&mut self) {
+        for cursor in self.cursors.iter_mut() {
+            cursor.target = Some(Target::Last(self.end.clone()));
+        }
+    }
+
+    /// Returns the entry before the position of the iterator and moves the position before it,
+    /// walking the keys in decreasing order
+    pub fn prev(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            for cursor in self.cursors.iter_mut() {
+                if let Err(e) = cursor.prepare_prev(self.verify_checksums) {
+                    return Some(Err(e));
+                }
+            }
+
+            // the largest key among the cursors, the newest source wins on equal keys
+            let mut newest: Option<(usize, &[u8])> = None;
+            for (i, cursor) in self.cursors.iter().enumerate() {
+                if let Some(key) = cursor.prev_key() {
+                    if newest.is_none_or(|(_, max_key)| key > max_key) {
+                        newest = Some((i, key));
+                    }
+                }
+            }
+            let (i, key) = newest?;
+            if !self.after_start(key) || !self.before_end(key) {
+                return None;
+            }
+            let key = key.to_vec();
+            let cursor = &self.cursors[i];
+            let value = cursor.entries[cursor.pos - 1].1.clone();
+
+            // skip the older versions of the key
+            for cursor in self.cursors.iter_mut() {
+                if cursor.prev_key() == Some(key.as_slice()) {
+                    cursor.pos -= 1;
+                }
+            }
+
+            if let MemValue::Value(value) = value {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+
+    // Returns the newest version of the next key, which is a delete marker if the key is deleted
+    pub(crate) fn next_entry(&mut self) -> Option<Result<(Vec<u8>, MemValue)>> {
+        for cursor in self.cursors.iter_mut() {
+            if let Err(e) = cursor.prepare_next(self.verify_checksums) {
+                return Some(Err(e));
+            }
+        }
+
+        // the smallest key among the cursors, the newest source wins on equal keys
+        let mut newest: Option<(usize, &[u8])> = None;
+        for (i, cursor) in self.cursors.iter().enumerate() {
+            if let Some(key) = cursor.key() {
+                if newest.is_none_or(|(_, min_key)| key < min_key) {
+                    newest = Some((i, key));
+                }
+            }
+        }
+        let (i, key) = newest?;
+        if !self.after_start(key) || !self.before_end(key) {
+            return None;
+        }
+        let key = key.to_vec();
+        let cursor = &self.cursors[i];
+        let value = cursor.entries[cursor.pos].1.clone();
+
+        // skip the older versions of the key
+        for cursor in self.cursors.iter_mut() {
+            if cursor.key() == Some(key.as_slice()) {
+                cursor.pos += 1;
+            }
+        }
+        Some(Ok((key, value)))
+    }
+
+    fn after_start(&self, key: &[u8]) -> bool {
+        match self.start {
+            Bound::Included(ref start) => key >= start.as_slice(),
+            Bound::Excluded(ref start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
+        match self.end {
+            Bound::Included(ref end) => key <= end.as_slice(),
+            Bound::Excluded(ref end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+// Returns the bound on the other side of the same key, which positions a cursor moving in the other direction
+// at the same place: seeking to the first entry after `..=key` stops where seeking to the last entry before `key..`
+// does, and the other way around
+fn other_side(bound: &Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Excluded(key.clone()),
+        Bound::Excluded(key) => Bound::Included(key.clone()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// returns the position of the first of the sorted entries that is after the bound
+fn first_position(entries: &[(Vec<u8>, MemValue)], bound: Bound<&[u8]>) -> usize {
+    match bound {
+        Bound::Included(key) => entries.partition_point(|(entry_key, _)| entry_key.as_slice() < key),
+        Bound::Excluded(key) => entries.partition_point(|(entry_key, _)| entry_key.as_slice() <= key),
+        Bound::Unbounded => 0,
+    }
+}
+
+// returns the position of the last of the sorted entries that is before the bound, plus one
+fn last_position(entries: &[(Vec<u8>, MemValue)], bound: Bound<&[u8]>) -> usize {
+    match bound {
+        Bound::Included(key) => first_position(entries, Bound::Excluded(key)),
+        Bound::Excluded(key) => first_position(entries, Bound::Included(key)),
+        Bound::Unbounded => entries.len(),
+    }
+}
+
+// returns the end of the range of keys starting with prefix - the smallest key greater than all of them
+pub fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::MAX {
+            end.push(byte + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    // the prefix is empty or made of 0xff bytes only
+    Bound::Unbounded
+}
+
+/// Iterator over the key-value pairs of a range of the db in decreasing order of keys
+pub struct DBReverseIterator {
+    iter: DBIterator,
+}
+
+impl DBReverseIterator {
+    pub(crate) fn new(mut iter: DBIterator) -> DBReverseIterator {
+        iter.seek_to_last();
+        DBReverseIterator { iter }
+    }
+}
+
+impl Iterator for DBReverseIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.prev()
+    }
+}
+
+impl Iterator for DBIterator {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_entry()? {
+                Ok((key, MemValue::Value(value))) => return Some(Ok((key, value))),
+                Ok((_, MemValue::Delete)) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prefix_end, DBIterator, DBReverseIterator, Source};
+    use crate::memtable::MemValue;
+    use crate::table::{Table, TableBuilder};
+    use std::fs;
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    fn source(entries: &[(&str, Option<&str>)]) -> Source {
+        Source::Mem(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Some(value) => MemValue::Value(value.as_bytes().to_vec()),
+                        None => MemValue::Delete,
+                    };
+                    (key.as_bytes().to_vec(), value)
+                })
+                .collect(),
+        )
+    }
+
+    fn collect(iter: DBIterator) -> Vec<(String, String)> {
+        iter.map(|entry| {
+            let (key, value) = entry.unwrap();
+            (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_merge_newest_wins() {
+        let sources = || {
+            vec![
+                source(&[("b", None), ("d", Some("d2"))]),
+                source(&[("a", Some("a1")), ("b", Some("b1")), ("c", Some("c1")), ("d", Some("d1"))]),
+            ]
+        };
+        let iter = DBIterator::new(sources(), Bound::Unbounded, Bound::Unbounded, true);
+        let expected = vec![
+            ("a".to_string(), "a1".to_string()),
+            ("c".to_string(), "c1".to_string()),
+            ("d".to_string(), "d2".to_string()),
+        ];
+        assert_eq!(collect(iter), expected);
+
+        let start = Bound::Excluded(b"a".to_vec());
+        let end = Bound::Excluded(b"d".to_vec());
+        let iter = DBIterator::new(sources(), start, end, true);
+        assert_eq!(collect(iter), vec![("c".to_string(), "c1".to_string())]);
+    }
+
+    #[test]
+    fn test_seek() {
+        let sources = vec![
+            source(&[("a", Some("a2")), ("c", None)]),
+            source(&[("b", Some("b1")), ("c", Some("c1")), ("d", Some("d1"))]),
+        ];
+        let start = Bound::Included(b"b".to_vec());
+        let mut iter = DBIterator::new(sources, start, Bound::Unbounded, true);
+        // the deleted key is skipped
+        iter.seek("c");
+        assert_eq!(collect_next(&mut iter), Some("d".to_string()));
+        // seeking backwards and before the start of the range
+        iter.seek("a");
+        assert_eq!(collect_next(&mut iter), Some("b".to_string()));
+        iter.seek("e");
+        assert_eq!(collect_next(&mut iter), None);
+    }
+
+    #[test]
+    fn test_table_blocks() {
+        let db_name = "target/testiter1";
+        fs::create_dir_all(db_name).unwrap();
+        let mut table_builder = TableBuilder::new(db_name, 1, 0, 0);
+        for i in 0..1000 {
+            let value = MemValue::Value(format!("value{:020}", i).into_bytes());
+            table_builder.add(format!("key{:04}", i).as_bytes(), &value).unwrap();
+        }
+        table_builder.flush(false).unwrap();
+        let table = Arc::new(Table::open(db_name, 1, None, false, true).unwrap());
+        assert!(table.num_blocks() > 5);
+
+        let sources = || vec![source(&[("key0500", None)]), Source::Table(table.clone())];
+        let iter = DBIterator::new(sources(), Bound::Unbounded, Bound::Unbounded, true);
+        assert_eq!(collect(iter).len(), 999);
+        let iter = DBIterator::new(sources(), Bound::Unbounded, Bound::Unbounded, true);
+        assert_eq!(DBReverseIterator::new(iter).count(), 999);
+
+        // seeking reads the block containing the key and iteration continues across blocks
+        let mut iter = DBIterator::new(sources(), Bound::Unbounded, Bound::Unbounded, true);
+        iter.seek("key0499");
+        assert_eq!(collect_next(&mut iter), Some("key0499".to_string()));
+        assert_eq!(collect_next(&mut iter), Some("key0501".to_string()));
+        iter.seek_for_prev("key0700");
+        assert_eq!(collect_prev(&mut iter), Some("key0700".to_string()));
+        for i in (0..700).rev() {
+            if i != 500 {
+                assert_eq!(collect_prev(&mut iter), Some(format!("key{:04}", i)));
+            }
+        }
+        assert_eq!(collect_prev(&mut iter), None);
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_prev() {
+        let sources = || {
+            vec![
+                source(&[("b", None), ("d", Some("d2"))]),
+                source(&[("a", Some("a1")), ("b", Some("b1")), ("c", Some("c1")), ("d", Some("d1"))]),
+            ]
+        };
+        let iter = DBIterator::new(sources(), Bound::Unbounded, Bound::Unbounded, true);
+        let keys: Vec<_> = DBReverseIterator::new(iter)
+            .map(|entry| String::from_utf8(entry.unwrap().1).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["d2", "c1", "a1"]);
+
+        let end = Bound::Excluded(b"d".to_vec());
+        let mut iter = DBIterator::new(sources(), Bound::Unbounded, end, true);
+        iter.seek_for_prev("z");
+        assert_eq!(collect_prev(&mut iter), Some("c".to_string()));
+        // changing direction returns the same entry again
+        assert_eq!(collect_next(&mut iter), Some("c".to_string()));
+        assert_eq!(collect_next(&mut iter), None);
+        iter.seek_for_prev("b");
+        assert_eq!(collect_prev(&mut iter), Some("a".to_string()));
+        assert_eq!(collect_prev(&mut iter), None);
+    }
+
+    #[test]
+    fn test_seek_outside_range() {
+        let sources = || {
+            vec![source(&[
+                ("a", Some("a1")),
+                ("b", Some("b1")),
+                ("c", Some("c1")),
+                ("d", Some("d1")),
+                ("y", Some("y1")),
+            ])]
+        };
+        let bounds = vec![
+            (Bound::Included(b"b".to_vec()), Bound::Excluded(b"d".to_vec()), "c"),
+            (Bound::Included(b"b".to_vec()), Bound::Included(b"d".to_vec()), "d"),
+            (Bound::Excluded(b"a".to_vec()), Bound::Excluded(b"d".to_vec()), "c"),
+        ];
+        for (start, end, last) in bounds {
+            let mut iter = DBIterator::new(sources(), start, end, true);
+            // seeking past the end and stepping back returns the last key of the range
+            iter.seek("z");
+            assert_eq!(collect_prev(&mut iter), Some(last.to_string()));
+            iter.seek("z");
+            assert_eq!(collect_next(&mut iter), None);
+            iter.seek_for_prev("z");
+            assert_eq!(collect_prev(&mut iter), Some(last.to_string()));
+
+            // seeking before the start and stepping forward returns the first key of the range
+            iter.seek_for_prev("0");
+            assert_eq!(collect_next(&mut iter), Some("b".to_string()));
+            iter.seek_for_prev("0");
+            assert_eq!(collect_prev(&mut iter), None);
+            iter.seek("0");
+            assert_eq!(collect_prev(&mut iter), None);
+            assert_eq!(collect_next(&mut iter), Some("b".to_string()));
+        }
+    }
+
+    fn collect_prev(iter: &mut DBIterator) -> Option<String> {
+        iter.prev()
+            .map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+    }
+
+    fn collect_next(iter: &mut DBIterator) -> Option<String> {
+        iter.next()
+            .map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"user:"), Bound::Excluded(b"user;".to_vec()));
+        assert_eq!(prefix_end(b"a\xff\xff"), Bound::Excluded(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), Bound::Unbounded);
+        assert_eq!(prefix_end(b""), Bound::Unbounded);
+    }
+}
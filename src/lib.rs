@@ -1,20 +1,73 @@
 extern crate byteorder;
 
+// returns the value, or None for a delete marker, from the enclosing function if the key was found
+macro_rules! get_mem_value {
+    ($e:expr) => {
+        match $e {
+            Some(MemValue::Value(value)) => return Ok(Some(value)),
+            Some(MemValue::Delete) => return Ok(None),
+            _ => (),
+        };
+    };
+}
+
 mod cache;
+mod compaction;
 pub mod db;
 pub mod errors;
+mod filter;
+mod iterator;
+mod lock;
+mod manifest;
 mod memtable;
 pub mod params;
+mod repair;
+mod snapshot;
 mod table;
+mod wal;
+mod write_batch;
 
+pub use compaction::{CompactionFilter, CompactionStats, FilterDecision};
 pub use db::DB;
-pub use errors::{Error, Result};
-pub use params::DBParams;
+pub use errors::{Corruption, Error, Result};
+pub use iterator::{DBIterator, DBReverseIterator};
+pub use params::{CompactionStrategy, DBParams, ReadOptions, SyncPolicy, WriteOptions};
+pub use repair::RepairReport;
+pub use snapshot::Snapshot;
+pub use write_batch::WriteBatch;
+
+// Entry points of the fuzz targets in fuzz/, only built with the fuzzing feature
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing {
+    pub fn decode_index(buf: &[u8]) {
+        let _ = crate::table::decode_index(buf, 0, false);
+        let _ = crate::table::decode_index(buf, 0, true);
+    }
+
+    pub fn decode_data(buf: &[u8]) {
+        let _ = crate::table::decode_block(buf, 0, 0, false);
+        let _ = crate::table::decode_block(buf, 0, 0, true);
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use crate::compaction::{CompactionFilter, CompactionStats, FilterDecision};
     use crate::db::DB;
-    use crate::params::DBParams;
+    use crate::params::{CompactionStrategy, DBParams, SyncPolicy, WriteOptions};
+    use crate::write_batch::WriteBatch;
+    use std::time::Duration;
+    use crate::errors::Error;
+    use crate::filter::BloomFilter;
+    use crate::iterator::DBIterator;
+    use crate::manifest::Manifest;
+    use crate::table::Table;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::path::Path;
+    use std::thread;
     use std::{fs, str};
 
     fn delete_db(db_name: &str) {
@@ -25,20 +78,20 @@ mod tests {
     fn test_db_open() {
         let db_params = DBParams::new();
         let db_name = "target/testdb1";
-        let db = DB::open(&db_name, db_params).unwrap();
+        let db = DB::open(db_name, db_params).unwrap();
         db.put("test", "value").unwrap();
         let val = db.get("test").unwrap().unwrap();
         assert_eq!(str::from_utf8(&val).unwrap(), "value");
         db.close().unwrap();
-        delete_db(&db_name);
+        delete_db(db_name);
     }
 
     #[test]
     fn test_db_get_and_put() {
         let mut db_params = DBParams::new();
-        db_params.write_buffer_size = 16;
+        db_params.write_buffer_size = 1 << 10;
         let db_name = "target/testdb2";
-        let db = DB::open(&db_name, db_params).unwrap();
+        let db = DB::open(db_name, db_params).unwrap();
 
         for i in 0..100 {
             db.put(i.to_string(), i.to_string()).unwrap();
@@ -49,6 +102,1023 @@ mod tests {
         }
 
         db.close().unwrap();
-        delete_db(&db_name);
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_reopen() {
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db_name = "target/testdb5";
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..100 {
+            db.put(i.to_string(), i.to_string()).unwrap();
+        }
+        for i in 0..10 {
+            db.delete(i.to_string()).unwrap();
+        }
+        db.close().unwrap();
+
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        for i in 0..10 {
+            assert!(db.get(i.to_string()).unwrap().is_none());
+        }
+        for i in 10..100 {
+            let val = db.get(i.to_string()).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_lock() {
+        let db_name = "target/testdb7";
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        match DB::open(db_name, DBParams::new()) {
+            Err(Error::DBLockedError) => (),
+            _ => panic!("db opened twice"),
+        }
+        match DB::repair(db_name) {
+            Err(Error::DBLockedError) => (),
+            _ => panic!("db repaired while in use"),
+        }
+        db.close().unwrap();
+
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_repair() {
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.auto_compaction = false;
+        let db_name = "target/testdb6";
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..100 {
+            db.put(i.to_string(), i.to_string()).unwrap();
+        }
+        db.close().unwrap();
+
+        // lose the manifest and damage one of the log tables
+        let mut tables = Vec::new();
+        for entry in fs::read_dir(db_name).unwrap() {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            if file_name == "CURRENT" || file_name.starts_with("MANIFEST-") {
+                fs::remove_file(format!("{}/{}", db_name, file_name)).unwrap();
+            } else if let Some(file_no) = file_name.strip_suffix(".ix") {
+                tables.push(file_no.parse::<u64>().unwrap());
+            }
+        }
+        tables.sort_unstable();
+        assert!(tables.len() > 3);
+        let damaged = format!("{}/{}.dt", db_name, tables[3]);
+        let mut buf = fs::read(&damaged).unwrap();
+        buf[8] ^= 1;
+        fs::write(&damaged, buf).unwrap();
+        assert!(DB::open(db_name, DBParams::new()).is_err());
+
+        let report = DB::repair(db_name).unwrap();
+        assert_eq!(report.lost_tables, vec![tables[3]]);
+        assert_eq!(report.recovered_tables.len(), tables.len() - 1);
+        let lost = Table::open(&format!("{}/lost", db_name), tables[3], None, false, true).unwrap();
+
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        let mut found = 0;
+        for i in 0..100 {
+            if let Some(val) = db.get(i.to_string()).unwrap() {
+                assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+                found += 1;
+            }
+        }
+        let lost_entries: usize = (0..lost.num_blocks())
+            .map(|block| lost.read_block(block, false).unwrap().len())
+            .sum();
+        assert_eq!(found, 100 - lost_entries);
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_repair_order() {
+        let db_name = "target/testdb23";
+        let write = |value: &str, num_keys: usize, db_params: DBParams| {
+            let db = DB::open(db_name, db_params).unwrap();
+            for i in 0..num_keys {
+                db.put(format!("key{:02}", i), value.to_string()).unwrap();
+            }
+            db.close().unwrap();
+        };
+        let check = || {
+            let db = DB::open(db_name, DBParams::new()).unwrap();
+            for (i, expected) in ["newest", "newer", "new", "old"].iter().enumerate() {
+                for j in [0, 5, 10, 25][i]..[5, 10, 25, 50][i] {
+                    assert_eq!(db.get(format!("key{:02}", j)).unwrap().unwrap(), expected.as_bytes());
+                }
+            }
+            db.close().unwrap();
+        };
+        let mut manual = DBParams::new();
+        manual.auto_compaction = false;
+        let mut compact_level0 = DBParams::new();
+        compact_level0.level0_compaction_trigger = 1;
+
+        write("old", 50, manual.clone());
+        // keep the write-ahead log of a table whose flush is interrupted before its log is removed
+        let db = DB::open(db_name, manual.clone()).unwrap();
+        let write_options = WriteOptions { sync: true };
+        for i in 0..25 {
+            db.put_with_options(format!("key{:02}", i), "new".to_string(), &write_options).unwrap();
+        }
+        let log_files: Vec<_> = fs::read_dir(db_name)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "wal"))
+            .map(|path| (path.clone(), fs::read(path).unwrap()))
+            .collect();
+        db.close().unwrap();
+        write("newer", 10, compact_level0);
+        // the newest values stay in level 0 while the older ones are compacted into deeper levels, into tables
+        // with greater file numbers
+        write("newest", 5, manual);
+        let mut compact_levels = DBParams::new();
+        compact_levels.level0_compaction_trigger = 100;
+        compact_levels.max_bytes_for_level_base = 1;
+        let db = DB::open(db_name, compact_levels).unwrap();
+        db.close().unwrap();
+        assert!(db.get_property("indydb.num-compactions").unwrap().unwrap() != "0");
+        check();
+
+        // the log flushed before the manifest was last written is not replayed
+        for (path, buf) in log_files {
+            fs::write(path, buf).unwrap();
+        }
+        let report = DB::repair(db_name).unwrap();
+        assert!(report.recovered_logs.is_empty());
+        check();
+
+        // without the manifest, the tables are placed in the levels they were written to
+        for entry in fs::read_dir(db_name).unwrap() {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            if file_name == "CURRENT" || file_name.starts_with("MANIFEST-") {
+                fs::remove_file(format!("{}/{}", db_name, file_name)).unwrap();
+            }
+        }
+        DB::repair(db_name).unwrap();
+        check();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_repair_log() {
+        let db_name = "target/testdb25";
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        db.put("key", "old").unwrap();
+        // flush the mem_table, whose log was allocated its number before the table it is flushed to
+        db.compact_range(Some(b"none"), Some(b"none")).unwrap();
+        db.put("key", "new").unwrap();
+        db.crash();
+
+        let report = DB::repair(db_name).unwrap();
+        assert_eq!(report.recovered_logs.len(), 1);
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        assert_eq!(db.get("key").unwrap().unwrap(), b"new");
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_recover_from_log() {
+        let db_name = "target/testdb3";
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        for i in 0..100 {
+            db.put(i.to_string(), i.to_string()).unwrap();
+        }
+        db.delete("0").unwrap();
+        // the mem_table is never flushed
+        db.crash();
+
+        // stale filters left behind by an interrupted flush, which rule out every key, must not be
+        // picked up by the log table recovered under the same file number
+        for file_no in 0..20 {
+            if !Path::new(&format!("{}/{}.ix", db_name, file_no)).exists() {
+                BloomFilter::build(&[], 10).write(db_name, file_no, false).unwrap();
+            }
+        }
+        let mut db_params = DBParams::new();
+        db_params.bloom_bits_per_key = 0;
+        let db = DB::open(db_name, db_params).unwrap();
+        assert!(db.get("0").unwrap().is_none());
+        for i in 1..100 {
+            let val = db.get(i.to_string()).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_write_batch() {
+        let db_name = "target/testdb8";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db = DB::open(db_name, db_params).unwrap();
+        db.put("deleted", "value").unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..50 {
+            batch.put(i.to_string(), i.to_string());
+        }
+        batch.delete("deleted");
+        batch.put("0", "overwritten");
+        assert_eq!(batch.len(), 52);
+        db.write(&batch).unwrap();
+        db.crash();
+
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        assert!(db.get("deleted").unwrap().is_none());
+        assert_eq!(db.get("0").unwrap().unwrap(), b"overwritten");
+        for i in 1..50 {
+            let val = db.get(i.to_string()).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_flush_queue() {
+        for &max_flush_tables in [1, 4].iter() {
+            let db_name = format!("target/testdb9_{}", max_flush_tables);
+            let mut db_params = DBParams::new();
+            db_params.write_buffer_size = 256;
+            db_params.max_flush_tables = max_flush_tables;
+            let db = DB::open(&db_name, db_params).unwrap();
+            for i in 0..200 {
+                db.put(i.to_string(), i.to_string()).unwrap();
+                // recent writes are readable while they are queued to be flushed
+                let val = db.get((i / 2).to_string()).unwrap().unwrap();
+                assert_eq!(str::from_utf8(&val).unwrap(), (i / 2).to_string());
+            }
+            db.close().unwrap();
+
+            let db = DB::open(&db_name, DBParams::new()).unwrap();
+            for i in 0..200 {
+                let val = db.get(i.to_string()).unwrap().unwrap();
+                assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+            }
+            db.close().unwrap();
+            delete_db(&db_name);
+        }
+    }
+
+    #[test]
+    fn test_db_memtable_usage() {
+        let db_name = "target/testdb10";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 16;
+        let db = DB::open(db_name, db_params).unwrap();
+        let property = |name| -> usize { db.get_property(name).unwrap().unwrap().parse().unwrap() };
+        assert_eq!(property("indydb.cur-size-active-mem-table"), 0);
+
+        // a few large values fill the mem_table long before it holds many entries
+        let value = vec![b'v'; 1 << 14];
+        for i in 0..3 {
+            db.put(i.to_string().as_bytes(), &value).unwrap();
+        }
+        assert_eq!(property("indydb.num-entries-active-mem-table"), 3);
+        assert!(property("indydb.cur-size-active-mem-table") > 3 << 14);
+        db.put("3".as_bytes(), &value).unwrap();
+        assert_eq!(property("indydb.num-entries-active-mem-table"), 0);
+        assert!(db.get_property("unknown").unwrap().is_none());
+
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_iter() {
+        let db_name = "target/testdb11";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db = DB::open(db_name, db_params).unwrap();
+
+        // overwrites and deletes spread over the log tables, the flush_tables and the mem_table
+        let mut expected = BTreeMap::new();
+        for i in 0..300 {
+            let key = format!("{:03}", i * 7 % 100);
+            if i % 5 == 4 {
+                db.delete(&key).unwrap();
+                expected.remove(&key);
+            } else {
+                let value = i.to_string();
+                db.put(&key, &value).unwrap();
+                expected.insert(key, value);
+            }
+        }
+
+        let collect = |iter: DBIterator| -> Vec<(String, String)> {
+            iter.map(|entry| {
+                let (key, value) = entry.unwrap();
+                (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap())
+            })
+            .collect()
+        };
+        let all: Vec<_> = expected.clone().into_iter().collect();
+        assert_eq!(collect(db.iter().unwrap()), all);
+        let range: Vec<_> = expected
+            .range("020".to_string().."050".to_string())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        assert_eq!(collect(db.range("020".."050").unwrap()), range);
+        let range: Vec<_> = expected
+            .range("090".to_string()..)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        assert_eq!(collect(db.range("090"..).unwrap()), range);
+        assert!(db.range("050".."020").unwrap().next().is_none());
+
+        // reverse iteration sees the same entries in decreasing order
+        let rev: Vec<_> = expected
+            .range("020".to_string()..="050".to_string())
+            .rev()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let iter = db.range_rev("020"..="050").unwrap().map(|entry| {
+            let (key, value) = entry.unwrap();
+            (String::from_utf8(key).unwrap(), String::from_utf8(value).unwrap())
+        });
+        assert_eq!(iter.collect::<Vec<_>>(), rev);
+        let mut iter = db.iter().unwrap();
+        iter.seek_for_prev("049");
+        let (key, _) = iter.prev().unwrap().unwrap();
+        assert_eq!(&key, expected.range(..="049".to_string()).last().unwrap().0.as_bytes());
+
+        // the same entries are returned once everything is flushed
+        db.close().unwrap();
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        assert_eq!(collect(db.iter().unwrap()), all);
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_prefix_iter() {
+        let db_name = "target/testdb12";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db = DB::open(db_name, db_params).unwrap();
+        for user in 0..20 {
+            for field in ["email", "name", "profile"].iter() {
+                db.put(format!("user:{:02}:{}", user, field), field.to_string()).unwrap();
+            }
+        }
+        db.put("user", "no separator").unwrap();
+        db.put("user;", "past the prefix").unwrap();
+        db.delete("user:07:name").unwrap();
+
+        let keys = |iter: DBIterator| -> Vec<String> {
+            iter.map(|entry| String::from_utf8(entry.unwrap().0).unwrap())
+                .collect()
+        };
+        assert_eq!(
+            keys(db.prefix_iter("user:07:").unwrap()),
+            vec!["user:07:email", "user:07:profile"]
+        );
+        assert_eq!(keys(db.prefix_iter("user:").unwrap()).len(), 59);
+
+        // seek jumps to the first key at or after the target
+        let mut iter = db.prefix_iter("user:").unwrap();
+        iter.seek("user:15:m");
+        let (key, value) = iter.next().unwrap().unwrap();
+        assert_eq!(key, b"user:15:name");
+        assert_eq!(value, b"name");
+        iter.seek("user:19:q");
+        assert!(iter.next().is_none());
+
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_snapshot() {
+        let db_name = "target/testdb13";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..50 {
+            db.put(i.to_string(), "old".to_string()).unwrap();
+        }
+        let snapshot = db.snapshot().unwrap();
+        assert_eq!(db.get_property("indydb.num-snapshots").unwrap().unwrap(), "1");
+
+        // newer writes and the flushes they cause are not visible to the snapshot
+        for i in 0..100 {
+            if i % 10 == 0 {
+                db.delete(i.to_string()).unwrap();
+            } else {
+                db.put(i.to_string(), "new".to_string()).unwrap();
+            }
+        }
+        for i in 0..100 {
+            let expected = if i < 50 { Some(b"old".to_vec()) } else { None };
+            assert_eq!(snapshot.get(i.to_string()).unwrap(), expected, "{}", i);
+        }
+        let values: Vec<_> = snapshot.iter().unwrap().map(|entry| entry.unwrap().1).collect();
+        assert_eq!(values, vec![b"old".to_vec(); 50]);
+        assert_eq!(snapshot.range_rev("10"..="19").unwrap().count(), 10);
+        assert_eq!(db.get("5").unwrap().unwrap(), b"new");
+        assert!(db.get("10").unwrap().is_none());
+        assert_eq!(db.iter().unwrap().count(), 90);
+
+        drop(snapshot);
+        assert_eq!(db.get_property("indydb.num-snapshots").unwrap().unwrap(), "0");
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_bloom_filters() {
+        let db_name = "target/testdb14";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.auto_compaction = false;
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..200 {
+            db.put(format!("key{}", i), i.to_string()).unwrap();
+        }
+        db.close().unwrap();
+
+        let mut db_params = DBParams::new();
+        db_params.auto_compaction = false;
+        let db = DB::open(db_name, db_params).unwrap();
+        let property = |name| -> usize { db.get_property(name).unwrap().unwrap().parse().unwrap() };
+        let num_tables = fs::read_dir(db_name)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().unwrap_or_default() == "bf")
+            .count();
+        assert!(num_tables > 10);
+        for i in 0..100 {
+            assert!(db.get(format!("key{}.", i)).unwrap().is_none());
+        }
+        // almost every table whose key range contains a missing key is skipped
+        let hits = property("indydb.bloom-filter-hits");
+        let misses = property("indydb.bloom-filter-misses");
+        assert!(hits + misses <= 100 * num_tables);
+        assert!(hits > 100);
+        assert!(misses < num_tables * 5);
+        for i in 0..200 {
+            let val = db.get(format!("key{}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+
+        // lookups through a snapshot skip the same tables
+        let snapshot = db.snapshot().unwrap();
+        let hits_before = property("indydb.bloom-filter-hits");
+        let misses_before = property("indydb.bloom-filter-misses");
+        for i in 0..100 {
+            assert!(snapshot.get(format!("key{}.", i)).unwrap().is_none());
+        }
+        let snapshot_hits = property("indydb.bloom-filter-hits") - hits_before;
+        let snapshot_misses = property("indydb.bloom-filter-misses") - misses_before;
+        assert_eq!((snapshot_hits, snapshot_misses), (hits, misses));
+        for i in 0..200 {
+            let val = snapshot.get(format!("key{}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        drop(snapshot);
+        db.close().unwrap();
+
+        // with a cache too small to hold any table, the filters are loaded again along with their tables
+        let mut db_params = DBParams::new();
+        db_params.auto_compaction = false;
+        db_params.cache_size = 1;
+        let db = DB::open(db_name, db_params).unwrap();
+        let property = |name| -> usize { db.get_property(name).unwrap().unwrap().parse().unwrap() };
+        for _ in 0..2 {
+            for i in 0..100 {
+                assert!(db.get(format!("key{}.", i)).unwrap().is_none());
+            }
+        }
+        assert!(property("indydb.bloom-filter-hits") > 200);
+        assert_eq!(property("indydb.table-cache-usage"), 0);
+        for i in 0..200 {
+            assert!(db.get(format!("key{}", i)).unwrap().is_some());
+        }
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_block_cache() {
+        let db_name = "target/testdb15";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..200 {
+            db.put(format!("key{}", i), i.to_string()).unwrap();
+        }
+        db.close().unwrap();
+
+        let mut db_params = DBParams::new();
+        db_params.block_cache_size = 1 << 20;
+        let db = DB::open(db_name, db_params).unwrap();
+        let property = |name| -> usize { db.get_property(name).unwrap().unwrap().parse().unwrap() };
+        for i in 0..200 {
+            let val = db.get(format!("key{}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        let misses = property("indydb.block-cache-misses");
+        assert!(misses > 0);
+        assert!(property("indydb.block-cache-usage") > 0);
+        // every block is now cached
+        let hits = property("indydb.block-cache-hits");
+        for i in 0..200 {
+            assert!(db.get(format!("key{}", i)).unwrap().is_some());
+        }
+        assert_eq!(property("indydb.block-cache-misses"), misses);
+        // false positives of the bloom filters also read blocks
+        assert!(property("indydb.block-cache-hits") >= hits + 200);
+        db.close().unwrap();
+
+        let mut db_params = DBParams::new();
+        db_params.block_cache_size = 0;
+        let db = DB::open(db_name, db_params).unwrap();
+        assert_eq!(db.iter().unwrap().count(), 200);
+        assert_eq!(db.get_property("indydb.block-cache-usage").unwrap().unwrap(), "0");
+        db.close().unwrap();
+
+        let mut db_params = DBParams::new();
+        db_params.mmap_reads = true;
+        let db = DB::open(db_name, db_params).unwrap();
+        assert_eq!(db.iter().unwrap().count(), 200);
+        for i in 0..200 {
+            let val = db.get(format!("key{}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_max_open_files() {
+        let db_name = "target/testdb16";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.max_open_files = 2;
+        db_params.block_cache_size = 0;
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..200 {
+            db.put(format!("key{}", i), i.to_string()).unwrap();
+        }
+        // tables are evicted and reopened as the lookups go through more tables than can be kept open
+        for _ in 0..2 {
+            for i in 0..200 {
+                let val = db.get(format!("key{}", i)).unwrap().unwrap();
+                assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+            }
+        }
+        assert_eq!(db.iter().unwrap().count(), 200);
+        db.close().unwrap();
+
+        // the cache of log tables is charged the memory taken up by their indexes and filters
+        let mut db_params = DBParams::new();
+        db_params.block_cache_size = 0;
+        let db = DB::open(db_name, db_params.clone()).unwrap();
+        for i in 0..200 {
+            assert!(db.get(format!("key{}", i)).unwrap().is_some());
+        }
+        let usage: usize = db.get_property("indydb.table-cache-usage").unwrap().unwrap().parse().unwrap();
+        assert!(usage > 0);
+        db.close().unwrap();
+
+        db_params.cache_size = usage / 2;
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..200 {
+            let val = db.get(format!("key{}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        let limited_usage: usize = db.get_property("indydb.table-cache-usage").unwrap().unwrap().parse().unwrap();
+        assert!(limited_usage <= usage / 2);
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    // number of entries, delete markers included, in the log tables of the db
+    fn count_table_entries(db_name: &str) -> usize {
+        let mut entries = 0;
+        for entry in fs::read_dir(db_name).unwrap() {
+            let file_name = entry.unwrap().file_name().into_string().unwrap();
+            if let Some(file_no) = file_name.strip_suffix(".ix") {
+                let table = Table::open(db_name, file_no.parse().unwrap(), None, false, true).unwrap();
+                for block in 0..table.num_blocks() {
+                    entries += table.read_block(block, true).unwrap().len();
+                }
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn test_db_compaction() {
+        let db_name = "target/testdb17";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.target_table_size = 4 << 10;
+        let db = DB::open(db_name, db_params).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..500 {
+            db.put(format!("key{:03}", i), format!("value{}", i)).unwrap();
+            expected.insert(format!("key{:03}", i), format!("value{}", i));
+        }
+        let snapshot = db.snapshot().unwrap();
+        for i in (0..500).step_by(2) {
+            db.put(format!("key{:03}", i), format!("new{}", i)).unwrap();
+            expected.insert(format!("key{:03}", i), format!("new{}", i));
+        }
+        for i in (0..500).step_by(3) {
+            db.delete(format!("key{:03}", i)).unwrap();
+            expected.remove(&format!("key{:03}", i));
+        }
+        db.close().unwrap();
+        assert!(db.get_property("indydb.num-compactions").unwrap().unwrap() != "0");
+
+        // the snapshot still reads the tables that were compacted away
+        for i in 0..500 {
+            let val = snapshot.get(format!("key{:03}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), format!("value{}", i));
+        }
+        drop(snapshot);
+
+        // compact the tables left in level 0, which leaves only the newest version of the live keys
+        let mut db_params = DBParams::new();
+        db_params.level0_compaction_trigger = 1;
+        let db = DB::open(db_name, db_params).unwrap();
+        let entries: Vec<_> = db.iter().unwrap().map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries.len(), expected.len());
+        for (key, value) in expected.iter() {
+            assert_eq!(db.get(key).unwrap().unwrap(), value.as_bytes());
+        }
+        db.close().unwrap();
+        assert_eq!(count_table_entries(db_name), expected.len());
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_leveled_compaction() {
+        let db_name = "target/testdb18";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.target_table_size = 1 << 10;
+        db_params.num_levels = 4;
+        db_params.max_bytes_for_level_base = 4 << 10;
+        db_params.max_bytes_for_level_multiplier = 4;
+        let db = DB::open(db_name, db_params.clone()).unwrap();
+        let mut expected = BTreeMap::new();
+        for round in 0..2 {
+            for i in 0..2000 {
+                let key = format!("key{:04}", i * 7919 % 2000);
+                let value = format!("value{}.{}", round, i);
+                db.put(&key, &value).unwrap();
+                expected.insert(key, value);
+            }
+        }
+        db.close().unwrap();
+
+        let tables: Vec<_> = Manifest::read(db_name).unwrap().live_tables().cloned().collect();
+        let mut max_bytes = db_params.max_bytes_for_level_base;
+        for level in 1..db_params.num_levels {
+            let mut level_tables: Vec<_> = tables.iter().filter(|table| table.level == level).collect();
+            level_tables.sort_by(|a, b| a.smallest.cmp(&b.smallest));
+            // the key ranges of a level below 0 are disjoint
+            for pair in level_tables.windows(2) {
+                assert!(pair[0].largest < pair[1].smallest);
+            }
+            // and only the last level may exceed its size
+            if level < db_params.num_levels - 1 {
+                assert!(level_tables.iter().map(|table| table.size).sum::<u64>() <= max_bytes);
+            }
+            max_bytes *= db_params.max_bytes_for_level_multiplier;
+        }
+        assert!(tables.iter().any(|table| table.level >= 2));
+        assert!(tables.iter().filter(|table| table.level == 0).count() < db_params.level0_compaction_trigger);
+
+        db_params.auto_compaction = false;
+        let db = DB::open(db_name, db_params).unwrap();
+        let num_tables: usize = (0..4)
+            .map(|level| {
+                let property = format!("indydb.num-log-tables-at-level{}", level);
+                db.get_property(&property).unwrap().unwrap().parse::<usize>().unwrap()
+            })
+            .sum();
+        assert_eq!(num_tables, tables.len());
+        for (key, value) in expected.iter() {
+            assert_eq!(db.get(key).unwrap().unwrap(), value.as_bytes());
+        }
+        assert_eq!(db.iter().unwrap().count(), expected.len());
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_size_tiered_compaction() {
+        let db_name = "target/testdb19";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.compaction_strategy = CompactionStrategy::SizeTiered;
+        let db = DB::open(db_name, db_params.clone()).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..3000 {
+            let key = format!("key{:04}", i * 7919 % 1000);
+            db.put(&key, &format!("value{}", i)).unwrap();
+            expected.insert(key, format!("value{}", i));
+        }
+        db.close().unwrap();
+        assert!(db.get_property("indydb.num-compactions").unwrap().unwrap() != "0");
+
+        // every level below 0 is a single sorted run, and fewer runs than the trigger are left
+        let manifest = Manifest::read(db_name).unwrap();
+        assert_eq!(manifest.compaction_strategy(), Some(CompactionStrategy::SizeTiered));
+        let num_runs = manifest.level(0).len()
+            + (1..manifest.num_levels())
+                .filter(|&level| !manifest.level(level).is_empty())
+                .count();
+        assert!(num_runs < db_params.level0_compaction_trigger);
+
+        // the db is reopened with the strategy it was created with
+        let mut db_params = DBParams::new();
+        db_params.compaction_strategy = CompactionStrategy::Leveled;
+        let db = DB::open(db_name, db_params).unwrap();
+        for (key, value) in expected.iter() {
+            assert_eq!(db.get(key).unwrap().unwrap(), value.as_bytes());
+        }
+        assert_eq!(db.iter().unwrap().count(), expected.len());
+        db.close().unwrap();
+        let manifest = Manifest::read(db_name).unwrap();
+        assert_eq!(manifest.compaction_strategy(), Some(CompactionStrategy::SizeTiered));
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_compact_range() {
+        let db_name = "target/testdb20";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.auto_compaction = false;
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..1000 {
+            db.put(format!("key{:04}", i), format!("value{}", i)).unwrap();
+        }
+        for i in 0..900 {
+            db.delete(format!("key{:04}", i)).unwrap();
+        }
+
+        let num_tables = |db: &DB| {
+            let num_tables = db.get_property("indydb.num-log-tables").unwrap().unwrap();
+            num_tables.parse::<usize>().unwrap()
+        };
+        // a range without any log table has nothing to compact, but the mem_table is still flushed
+        let stats = db.compact_range(Some(b"zzz"), None).unwrap();
+        assert_eq!(stats, CompactionStats::default());
+        assert_eq!(db.get_property("indydb.num-entries-active-mem-table").unwrap().unwrap(), "0");
+
+        let tables_before = num_tables(&db);
+        let stats = db.compact_range(None, None).unwrap();
+        assert_eq!(stats.tables_read, tables_before);
+        assert_eq!(num_tables(&db), stats.tables_written);
+        assert!(stats.bytes_written < stats.bytes_read);
+        assert_eq!(stats.bytes_reclaimed, stats.bytes_read - stats.bytes_written);
+        assert!(db.get("key0000").unwrap().is_none());
+        assert_eq!(db.get("key0900").unwrap().unwrap(), b"value900");
+        assert_eq!(db.iter().unwrap().count(), 100);
+
+        // the deleted keys are gone from the log tables
+        assert_eq!(count_table_entries(db_name), 100);
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    // removes the expired values and strips the "old:" prefix of the others
+    struct ExpiryFilter;
+
+    impl CompactionFilter for ExpiryFilter {
+        fn filter(&self, _key: &[u8], value: &[u8]) -> FilterDecision {
+            if value.starts_with(b"expired") {
+                FilterDecision::Remove
+            } else if let Some(value) = value.strip_prefix(b"old:") {
+                FilterDecision::Replace(value.to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_compaction_filter() {
+        let db_name = "target/testdb21";
+        let mut db_params = DBParams::new();
+        db_params.level0_compaction_trigger = 1;
+        db_params.compaction_filter = Some(Arc::new(ExpiryFilter));
+        let db = DB::open(db_name, db_params).unwrap();
+        db.put("session1", "live").unwrap();
+        db.put("session2", "expired").unwrap();
+        db.put("row", "old:data").unwrap();
+        // the filter only runs on compactions
+        assert_eq!(db.get("session2").unwrap().unwrap(), b"expired");
+
+        db.compact_range(None, None).unwrap();
+        assert_eq!(db.get("session1").unwrap().unwrap(), b"live");
+        assert!(db.get("session2").unwrap().is_none());
+        assert_eq!(db.get("row").unwrap().unwrap(), b"data");
+        assert_eq!(count_table_entries(db_name), 2);
+
+        // removing a key whose older value is left in a deeper level keeps a delete marker hiding it
+        db.put("session1", "expired later").unwrap();
+        db.close().unwrap();
+        assert_eq!(count_table_entries(db_name), 3);
+
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        assert!(db.get("session1").unwrap().is_none());
+        assert_eq!(db.get("row").unwrap().unwrap(), b"data");
+        let entries: Vec<_> = db.iter().unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(entries, vec![b"row".to_vec()]);
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    // fails every compaction it takes part in
+    struct PanickingFilter;
+
+    impl CompactionFilter for PanickingFilter {
+        fn filter(&self, _key: &[u8], _value: &[u8]) -> FilterDecision {
+            panic!("compaction filter failed");
+        }
+    }
+
+    #[test]
+    fn test_db_failed_compaction() {
+        let db_name = "target/testdb22";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.level0_compaction_trigger = 2;
+        db_params.level0_stop_writes_trigger = 2;
+        db_params.compaction_filter = Some(Arc::new(PanickingFilter));
+        let db = DB::open(db_name, db_params).unwrap();
+        // the writes are neither stalled nor lost once compactions have failed
+        for i in 0..200 {
+            db.put(format!("key{:03}", i), i.to_string()).unwrap();
+        }
+        for i in 0..200 {
+            let val = db.get(format!("key{:03}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        match db.close() {
+            Err(Error::BackgroundCompactionError) => (),
+            _ => panic!("failed compaction not reported"),
+        }
+        assert_eq!(db.get_property("indydb.num-compactions").unwrap().unwrap(), "0");
+
+        let db = DB::open(db_name, DBParams::new()).unwrap();
+        for i in 0..200 {
+            let val = db.get(format!("key{:03}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_failed_flush() {
+        let db_name = "target/testdb26";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        let db = DB::open(db_name, db_params.clone()).unwrap();
+        // the data tables of the next flushes cannot be created
+        let blocked: Vec<_> = (0..100)
+            .map(|file_no| format!("{}/{}.dt", db_name, file_no))
+            .filter(|path| !Path::new(path).exists())
+            .collect();
+        for path in blocked.iter() {
+            fs::create_dir(path).unwrap();
+        }
+
+        // the writes are refused once a flush has failed, instead of stalling
+        let mut written = 0;
+        for i in 0..1000 {
+            match db.put(i.to_string(), i.to_string()) {
+                Ok(()) => written += 1,
+                Err(Error::BackgroundFlushError) => break,
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+        assert!(written < 1000);
+        for i in 0..written {
+            assert_eq!(db.get(i.to_string()).unwrap().unwrap(), i.to_string().as_bytes());
+        }
+        match db.close() {
+            Err(Error::IOError(_)) => (),
+            _ => panic!("failed flush not reported"),
+        }
+
+        // the writes are recovered from their logs
+        for path in blocked.iter() {
+            fs::remove_dir(path).unwrap();
+        }
+        let db = DB::open(db_name, db_params).unwrap();
+        for i in 0..written {
+            assert_eq!(db.get(i.to_string()).unwrap().unwrap(), i.to_string().as_bytes());
+        }
+        db.close().unwrap();
+        delete_db(db_name);
+    }
+
+    // slows compactions down, so that they overlap the writes and the reads
+    struct SlowFilter;
+
+    impl CompactionFilter for SlowFilter {
+        fn filter(&self, _key: &[u8], _value: &[u8]) -> FilterDecision {
+            thread::sleep(Duration::from_micros(100));
+            FilterDecision::Keep
+        }
+    }
+
+    #[test]
+    fn test_db_concurrent_compaction() {
+        let db_name = "target/testdb24";
+        let mut db_params = DBParams::new();
+        db_params.write_buffer_size = 1 << 10;
+        db_params.max_flush_tables = 1;
+        db_params.level0_compaction_trigger = 2;
+        db_params.level0_stop_writes_trigger = 2;
+        db_params.compaction_filter = Some(Arc::new(SlowFilter));
+        let db = Arc::new(DB::open(db_name, db_params).unwrap());
+        let done = Arc::new(AtomicBool::new(false));
+
+        // the readers take snapshots while writes stall on level 0 and compactions remove tables
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let db = db.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        let snapshot = db.snapshot().unwrap();
+                        let keys = db.iter().unwrap().count();
+                        assert!(snapshot.iter().unwrap().count() <= keys);
+                    }
+                })
+            })
+            .collect();
+        for i in 0..1000 {
+            db.put(format!("key{:04}", i), i.to_string()).unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        for i in 0..1000 {
+            let val = db.get(format!("key{:04}", i)).unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+        }
+        db.close().unwrap();
+        assert!(db.get_property("indydb.num-compactions").unwrap().unwrap() != "0");
+        delete_db(db_name);
+    }
+
+    #[test]
+    fn test_db_sync_policies() {
+        let policies = [
+            SyncPolicy::EveryWrite,
+            SyncPolicy::Batch(10),
+            SyncPolicy::Periodic(Duration::from_millis(10)),
+        ];
+        for (i, &policy) in policies.iter().enumerate() {
+            let db_name = format!("target/testdb4_{}", i);
+            let mut db_params = DBParams::new();
+            db_params.write_buffer_size = 1 << 10;
+            db_params.sync_policy = policy;
+            let db = DB::open(&db_name, db_params).unwrap();
+            for i in 0..50 {
+                db.put(i.to_string(), i.to_string()).unwrap();
+            }
+            let write_options = WriteOptions { sync: true };
+            db.put_with_options("key", "value", &write_options).unwrap();
+            db.delete_with_options("0", &write_options).unwrap();
+            db.close().unwrap();
+
+            let db = DB::open(&db_name, DBParams::new()).unwrap();
+            assert!(db.get("0").unwrap().is_none());
+            let val = db.get("key").unwrap().unwrap();
+            assert_eq!(str::from_utf8(&val).unwrap(), "value");
+            for i in 1..50 {
+                let val = db.get(i.to_string()).unwrap().unwrap();
+                assert_eq!(str::from_utf8(&val).unwrap(), i.to_string());
+            }
+            db.close().unwrap();
+            delete_db(&db_name);
+        }
     }
 }
@@ -0,0 +1,20 @@
+use std::fs::{File, OpenOptions, TryLockError};
+
+use crate::errors::{Error, Result};
+
+// Takes an exclusive advisory lock on the LOCK file of the db, so that only one DB object,
+// in this or any other process, uses the db at a time.
+// The lock is released when the returned file is closed. File::try_lock is what sets the minimum Rust version
+// of the crate (rust-version in Cargo.toml)
+pub fn lock_db(db_name: &str) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(format!("{}/LOCK", db_name))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(Error::DBLockedError),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
@@ -0,0 +1,524 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::errors::{Error, Result};
+use crate::params::CompactionStrategy;
+use crate::wal;
+
+// Version of the on-disk format written by this version of the library.
+// 1 - log tables without checksums
+// 2 - checksum after every index entry and data record
+// 3 - data files of checksummed blocks of sorted entries, with a sparse index of the first key of each block
+// 4 - compaction levels, sizes and key ranges of the log tables recorded in the manifest
+// 5 - compaction strategy of the db recorded in the manifest
+// 6 - level of the log table recorded in its index table
+pub const FORMAT_VERSION: u64 = 6;
+
+// Log table belonging to the db, along with what compaction needs to know about it
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableMeta {
+    pub file_no: u64,
+    // level 0 holds the tables flushed from mem_tables, which may overlap and are ordered by file number.
+    // Every deeper level holds tables with disjoint key ranges, and is older than all the levels above it
+    pub level: usize,
+    // size in bytes of the data and index tables
+    pub size: u64,
+    // smallest and largest keys of the table
+    pub smallest: Vec<u8>,
+    pub largest: Vec<u8>,
+}
+
+impl TableMeta {
+    // whether the key range of the table overlaps the range from start to end, None being unbounded
+    pub fn overlaps(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> bool {
+        start.is_none_or(|start| self.largest.as_slice() >= start)
+            && end.is_none_or(|end| self.smallest.as_slice() <= end)
+    }
+}
+
+// A change to the state of the db, recorded in the manifest
+#[derive(Clone, Debug, PartialEq)]
+pub enum VersionEdit {
+    // version of the on-disk format of the db
+    FormatVersion(u64),
+    // next unused file number
+    NextFileNumber(u64),
+    // write-ahead logs numbered below this have been flushed to log tables
+    LogNumber(u64),
+    // log table added to the db
+    AddTable(TableMeta),
+    // log table removed from the db
+    RemoveTable(u64),
+    // compaction strategy the db was created with
+    CompactionStrategy(CompactionStrategy),
+}
+
+#[repr(u8)]
+#[derive(FromPrimitive)]
+enum VersionEditCode {
+    FormatVersion = 0u8,
+    NextFileNumber = 1u8,
+    LogNumber = 2u8,
+    AddTable = 3u8,
+    RemoveTable = 4u8,
+    CompactionStrategy = 5u8,
+}
+
+impl VersionEdit {
+    // encodes the edit to on-disk format - <edit code><number>, where an added table is encoded as
+    // <edit code><file no><level><size><smallest key len><smallest key><largest key len><largest key>
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let (code, number) = match *self {
+            VersionEdit::FormatVersion(n) => (VersionEditCode::FormatVersion, n),
+            VersionEdit::NextFileNumber(n) => (VersionEditCode::NextFileNumber, n),
+            VersionEdit::LogNumber(n) => (VersionEditCode::LogNumber, n),
+            VersionEdit::AddTable(ref meta) => (VersionEditCode::AddTable, meta.file_no),
+            VersionEdit::RemoveTable(n) => (VersionEditCode::RemoveTable, n),
+            VersionEdit::CompactionStrategy(strategy) => {
+                let n = match strategy {
+                    CompactionStrategy::Leveled => 0,
+                    CompactionStrategy::SizeTiered => 1,
+                };
+                (VersionEditCode::CompactionStrategy, n)
+            }
+        };
+        buf.push(code as u8);
+        buf.write_u64::<BigEndian>(number)?;
+        if let VersionEdit::AddTable(ref meta) = *self {
+            buf.write_u64::<BigEndian>(meta.level as u64)?;
+            buf.write_u64::<BigEndian>(meta.size)?;
+            for key in [&meta.smallest, &meta.largest].iter() {
+                buf.write_u64::<BigEndian>(key.len() as u64)?;
+                buf.extend_from_slice(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(cursor: &mut Cursor<&[u8]>) -> Result<VersionEdit> {
+        let code = cursor.read_u8()?;
+        let number = cursor.read_u64::<BigEndian>()?;
+        let edit = match FromPrimitive::from_u8(code) {
+            Some(VersionEditCode::FormatVersion) => VersionEdit::FormatVersion(number),
+            Some(VersionEditCode::NextFileNumber) => VersionEdit::NextFileNumber(number),
+            Some(VersionEditCode::LogNumber) => VersionEdit::LogNumber(number),
+            Some(VersionEditCode::AddTable) => VersionEdit::AddTable(TableMeta {
+                file_no: number,
+                level: cursor.read_u64::<BigEndian>()? as usize,
+                size: cursor.read_u64::<BigEndian>()?,
+                smallest: decode_key(cursor)?,
+                largest: decode_key(cursor)?,
+            }),
+            Some(VersionEditCode::RemoveTable) => VersionEdit::RemoveTable(number),
+            Some(VersionEditCode::CompactionStrategy) => match number {
+                0 => VersionEdit::CompactionStrategy(CompactionStrategy::Leveled),
+                1 => VersionEdit::CompactionStrategy(CompactionStrategy::SizeTiered),
+                _ => return Err(Error::DBCorruptionError),
+            },
+            None => return Err(Error::DBCorruptionError),
+        };
+        Ok(edit)
+    }
+}
+
+// decodes <key len><key bytes>, validating the length against the remaining bytes
+fn decode_key(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>> {
+    let len = cursor.read_u64::<BigEndian>()?;
+    if len > cursor.get_ref().len() as u64 - cursor.position() {
+        return Err(Error::DBCorruptionError);
+    }
+    let mut key = vec![0; len as usize];
+    cursor.read_exact(&mut key)?;
+    Ok(key)
+}
+
+// Manifest keeps track of the log tables belonging to the db.
+// It is an append-only log of version edits stored in MANIFEST-<file no>, using the record format of
+// the write-ahead log, and the CURRENT file contains the name of the manifest in use.
+// Every time the db is opened a new manifest starting with a snapshot of the state of the db is written,
+// and CURRENT is switched to it through an atomic rename, so that the db always sees a consistent set of tables.
+pub struct Manifest {
+    db_name: String,
+    // manifest file that edits are appended to, set once the first snapshot is written
+    file: Option<File>,
+    manifest_no: u64,
+    format_version: u64,
+    next_file_no: u64,
+    log_no: u64,
+    // live log tables of every level, the tables of level 0 are ordered by file number and
+    // the tables of the deeper levels by key
+    levels: Vec<Vec<TableMeta>>,
+    // compaction strategy of the db, None until it is recorded
+    compaction_strategy: Option<CompactionStrategy>,
+    // sync the manifest and the directory after every change
+    sync: bool,
+}
+
+impl Manifest {
+    // Creates the manifest of a new db
+    pub fn create(db_name: &str, sync: bool) -> Result<Manifest> {
+        let mut manifest = Manifest::empty(db_name, sync);
+        manifest.write_snapshot()?;
+        Ok(manifest)
+    }
+
+    // Loads the manifest of an existing db and starts a new manifest with a snapshot of its state
+    pub fn open(db_name: &str, sync: bool) -> Result<Manifest> {
+        let (mut manifest, old_manifest) = Manifest::load(db_name, sync)?;
+        manifest.write_snapshot()?;
+        fs::remove_file(old_manifest)?;
+        Ok(manifest)
+    }
+
+    // Reads the state of the db from the manifest named by CURRENT without modifying any file.
+    // Returns the state along with the path of the manifest
+    fn load(db_name: &str, sync: bool) -> Result<(Manifest, String)> {
+        let mut manifest = Manifest::empty(db_name, sync);
+        match fs::read_to_string(format!("{}/CURRENT", db_name)) {
+            Ok(current) => {
+                let manifest_name = current.trim_end();
+                let mut buf = Vec::new();
+                File::open(format!("{}/{}", db_name, manifest_name))?.read_to_end(&mut buf)?;
+                for payload in wal::decode_records(&buf) {
+                    let mut cursor = Cursor::new(payload);
+                    while cursor.position() < payload.len() as u64 {
+                        manifest.apply(VersionEdit::decode(&mut cursor)?);
+                    }
+                }
+                if manifest.format_version != FORMAT_VERSION {
+                    return Err(Error::IncompatibleFormatError);
+                }
+                Ok((manifest, format!("{}/{}", db_name, manifest_name)))
+            }
+            // dbs of the older format, which only kept the number of log tables in METADATA
+            Err(_) if Path::new(&format!("{}/METADATA", db_name)).exists() => Err(Error::IncompatibleFormatError),
+            Err(_) => Err(Error::DBNameInvalidError),
+        }
+    }
+
+    // Reads the state of the db recorded in its manifest, if it can be read.
+    // The returned manifest is not backed by a file, so no edit can be logged to it
+    pub fn read(db_name: &str) -> Result<Manifest> {
+        let (manifest, _) = Manifest::load(db_name, false)?;
+        Ok(manifest)
+    }
+
+    // Creates a new manifest containing the given log tables and compaction strategy, replacing the manifest
+    // in use if any
+    pub fn rebuild(
+        db_name: &str,
+        tables: &[TableMeta],
+        compaction_strategy: Option<CompactionStrategy>,
+        next_file_no: u64,
+        sync: bool,
+    ) -> Result<Manifest> {
+        let mut manifest = Manifest::empty(db_name, sync);
+        for table in tables {
+            manifest.apply(VersionEdit::AddTable(table.clone()));
+        }
+        manifest.compaction_strategy = compaction_strategy;
+        manifest.next_file_no = next_file_no;
+        manifest.log_no = next_file_no;
+        manifest.write_snapshot()?;
+        Ok(manifest)
+    }
+
+    // manifest without any tables, not yet backed by a file
+    fn empty(db_name: &str, sync: bool) -> Manifest {
+        Manifest {
+            db_name: String::from(db_name),
+            file: None,
+            manifest_no: 0,
+            format_version: FORMAT_VERSION,
+            next_file_no: 0,
+            log_no: 0,
+            levels: Vec::new(),
+            compaction_strategy: None,
+            sync,
+        }
+    }
+
+    // Records the edits in the manifest and applies them.
+    // The edits are written as a single record, so either all or none of them are applied after a crash
+    pub fn log_and_apply(&mut self, mut edits: Vec<VersionEdit>) -> Result<()> {
+        edits.push(VersionEdit::NextFileNumber(self.next_file_no));
+        let mut payload = Vec::new();
+        for edit in edits.iter() {
+            edit.encode(&mut payload)?;
+        }
+        if self.sync {
+            // the files referenced by the edits must be durable before the manifest refers to them
+            sync_dir(&self.db_name)?;
+        }
+        let file = self.file.as_mut().expect("manifest has no snapshot");
+        file.write_all(&wal::encode_record(&payload)?)?;
+        if self.sync {
+            file.sync_data()?;
+        }
+        for edit in edits {
+            self.apply(edit);
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, edit: VersionEdit) {
+        match edit {
+            VersionEdit::FormatVersion(n) => self.format_version = n,
+            VersionEdit::NextFileNumber(n) => self.next_file_no = self.next_file_no.max(n),
+            VersionEdit::LogNumber(n) => self.log_no = self.log_no.max(n),
+            VersionEdit::AddTable(meta) => {
+                if self.levels.len() <= meta.level {
+                    self.levels.resize(meta.level + 1, Vec::new());
+                }
+                let tables = &mut self.levels[meta.level];
+                let pos = if meta.level == 0 {
+                    tables.binary_search_by_key(&meta.file_no, |table| table.file_no)
+                } else {
+                    tables.binary_search_by(|table| table.smallest.cmp(&meta.smallest))
+                };
+                if let Err(i) = pos {
+                    tables.insert(i, meta);
+                }
+            }
+            VersionEdit::RemoveTable(n) => {
+                for tables in self.levels.iter_mut() {
+                    tables.retain(|table| table.file_no != n);
+                }
+            }
+            VersionEdit::CompactionStrategy(strategy) => self.compaction_strategy = Some(strategy),
+        }
+    }
+
+    // writes the state of the db to a new manifest and points CURRENT to it
+    fn write_snapshot(&mut self) -> Result<()> {
+        let manifest_no = self.new_file_no();
+        let mut edits = vec![
+            VersionEdit::FormatVersion(FORMAT_VERSION),
+            VersionEdit::NextFileNumber(self.next_file_no),
+            VersionEdit::LogNumber(self.log_no),
+        ];
+        edits.extend(self.compaction_strategy.map(VersionEdit::CompactionStrategy));
+        edits.extend(self.levels.iter().flatten().map(|table| VersionEdit::AddTable(table.clone())));
+        let mut payload = Vec::new();
+        for edit in edits.iter() {
+            edit.encode(&mut payload)?;
+        }
+
+        let mut file = File::create(manifest_file_name(&self.db_name, manifest_no))?;
+        file.write_all(&wal::encode_record(&payload)?)?;
+        if self.sync {
+            file.sync_all()?;
+        }
+
+        // switch CURRENT to the new manifest
+        let current_tmp = format!("{}/CURRENT.tmp", self.db_name);
+        let mut current = File::create(&current_tmp)?;
+        current.write_all(format!("MANIFEST-{}\n", manifest_no).as_bytes())?;
+        if self.sync {
+            current.sync_all()?;
+        }
+        fs::rename(&current_tmp, format!("{}/CURRENT", self.db_name))?;
+        if self.sync {
+            sync_dir(&self.db_name)?;
+        }
+
+        self.file = Some(file);
+        self.manifest_no = manifest_no;
+        self.format_version = FORMAT_VERSION;
+        Ok(())
+    }
+
+    // Removes the files in the db directory that do not belong to the current state of the db:
+    // log tables that are not live, flushed write-ahead logs and old manifests.
+    // Must only be called while no table is being written
+    pub fn remove_obsolete_files(&self) -> Result<()> {
+        for entry in fs::read_dir(&self.db_name)? {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            let obsolete = match parse_file_name(&file_name) {
+                Some((file_no, "dt")) | Some((file_no, "ix")) | Some((file_no, "bf")) => {
+                    !self.levels.iter().flatten().any(|table| table.file_no == file_no)
+                }
+                Some((file_no, "wal")) => file_no < self.log_no,
+                Some((file_no, "MANIFEST")) => file_no != self.manifest_no,
+                _ => false,
+            };
+            if obsolete {
+                fs::remove_file(format!("{}/{}", self.db_name, file_name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // allocates a new file number
+    pub fn new_file_no(&mut self) -> u64 {
+        self.next_file_no += 1;
+        self.next_file_no - 1
+    }
+
+    // marks all file numbers below file_no as used
+    pub fn mark_file_no_used(&mut self, file_no: u64) {
+        self.next_file_no = self.next_file_no.max(file_no + 1);
+    }
+
+    pub fn log_no(&self) -> u64 {
+        self.log_no
+    }
+
+    // live log tables of the level, ordered by file number for level 0 and by key for the deeper levels
+    pub fn level(&self, level: usize) -> &[TableMeta] {
+        self.levels.get(level).map_or(&[], |tables| tables.as_slice())
+    }
+
+    pub fn compaction_strategy(&self) -> Option<CompactionStrategy> {
+        self.compaction_strategy
+    }
+
+    // live log tables of every level
+    pub fn live_tables(&self) -> impl Iterator<Item = &TableMeta> {
+        self.levels.iter().flatten()
+    }
+
+    // number of levels holding tables, or that held tables since the db was opened
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // live log tables ordered from newest to oldest, except that the tables of a level below 0 have
+    // disjoint key ranges and so are ordered by key
+    pub fn tables(&self) -> Vec<u64> {
+        self.ordered_tables().map(|table| table.file_no).collect()
+    }
+
+    // live log tables in the same order as tables(), along with their key ranges
+    pub fn table_metas(&self) -> Vec<TableMeta> {
+        self.ordered_tables().cloned().collect()
+    }
+
+    fn ordered_tables(&self) -> impl Iterator<Item = &TableMeta> {
+        self.level(0).iter().rev().chain(self.levels.iter().skip(1).flatten())
+    }
+
+    // live log tables whose key range contains the key, ordered from newest to oldest.
+    // At most one table of every level below 0 can contain the key
+    pub fn tables_for_key(&self, key: &[u8]) -> Vec<u64> {
+        let contains = |table: &TableMeta| table.overlaps(Some(key), Some(key));
+        let mut file_nos: Vec<u64> = self
+            .level(0)
+            .iter()
+            .rev()
+            .filter(|table| contains(table))
+            .map(|table| table.file_no)
+            .collect();
+        for tables in self.levels.iter().skip(1) {
+            let i = tables.partition_point(|table| table.largest.as_slice() < key);
+            if let Some(table) = tables.get(i).filter(|table| contains(table)) {
+                file_nos.push(table.file_no);
+            }
+        }
+        file_nos
+    }
+}
+
+pub fn manifest_file_name(db_name: &str, file_no: u64) -> String {
+    format!("{}/MANIFEST-{}", db_name, file_no)
+}
+
+// parses the name of a file belonging to the db into its file number and its type
+// - "dt", "ix", "wal" or "MANIFEST"
+pub fn parse_file_name(file_name: &str) -> Option<(u64, &str)> {
+    if let Some(file_no) = file_name.strip_prefix("MANIFEST-") {
+        return file_no.parse().ok().map(|file_no| (file_no, "MANIFEST"));
+    }
+    let mut parts = file_name.splitn(2, '.');
+    let file_no = parts.next()?.parse().ok()?;
+    Some((file_no, parts.next()?))
+}
+
+// syncs the directory entries of the db
+pub fn sync_dir(db_name: &str) -> Result<()> {
+    File::open(db_name)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, TableMeta, VersionEdit};
+    use crate::params::CompactionStrategy;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::prelude::*;
+
+    #[test]
+    fn test_manifest_reopen() {
+        let db_name = "target/testmanifest1";
+        fs::create_dir_all(db_name).unwrap();
+        let mut manifest = Manifest::create(db_name, false).unwrap();
+        let file_nos: Vec<u64> = (0..3).map(|_| manifest.new_file_no()).collect();
+        for &file_no in file_nos.iter() {
+            manifest
+                .log_and_apply(vec![VersionEdit::AddTable(meta(file_no, 0, b"a", b"m"))])
+                .unwrap();
+        }
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::RemoveTable(file_nos[1]),
+                VersionEdit::LogNumber(7),
+                VersionEdit::CompactionStrategy(CompactionStrategy::SizeTiered),
+            ])
+            .unwrap();
+        drop(manifest);
+
+        // a torn edit at the end of the manifest is ignored
+        let current = fs::read_to_string(format!("{}/CURRENT", db_name)).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(format!("{}/{}", db_name, current.trim_end()))
+            .unwrap();
+        file.write_all(&[0, 0, 0, 1, 0, 0]).unwrap();
+
+        let mut manifest = Manifest::open(db_name, false).unwrap();
+        assert_eq!(manifest.tables(), vec![file_nos[2], file_nos[0]]);
+        assert_eq!(manifest.level(0)[0], meta(file_nos[0], 0, b"a", b"m"));
+        assert_eq!(manifest.log_no(), 7);
+        assert_eq!(manifest.compaction_strategy(), Some(CompactionStrategy::SizeTiered));
+        assert!(manifest.new_file_no() > file_nos[2]);
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_tables_for_key() {
+        let db_name = "target/testmanifest2";
+        fs::create_dir_all(db_name).unwrap();
+        let mut manifest = Manifest::create(db_name, false).unwrap();
+        manifest
+            .log_and_apply(vec![
+                VersionEdit::AddTable(meta(5, 0, b"c", b"f")),
+                VersionEdit::AddTable(meta(7, 0, b"a", b"d")),
+                VersionEdit::AddTable(meta(3, 1, b"e", b"h")),
+                VersionEdit::AddTable(meta(2, 1, b"a", b"d")),
+            ])
+            .unwrap();
+        assert_eq!(manifest.tables(), vec![7, 5, 2, 3]);
+        assert_eq!(manifest.tables_for_key(b"c"), vec![7, 5, 2]);
+        assert_eq!(manifest.tables_for_key(b"e"), vec![5, 3]);
+        assert_eq!(manifest.tables_for_key(b"z"), Vec::<u64>::new());
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    fn meta(file_no: u64, level: usize, smallest: &[u8], largest: &[u8]) -> TableMeta {
+        TableMeta {
+            file_no,
+            level,
+            size: 100,
+            smallest: smallest.to_vec(),
+            largest: largest.to_vec(),
+        }
+    }
+}
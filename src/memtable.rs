@@ -1,4 +1,8 @@
-use chashmap::CHashMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
 
 use crate::errors::Result;
 
@@ -17,43 +21,183 @@ impl MemValue {
     }
 }
 
+// approximate memory taken by a key of the table besides its bytes:
+// the key and version list headers stored in the tree node plus the share of the node bookkeeping
+const KEY_OVERHEAD: usize = mem::size_of::<(Vec<u8>, Versions)>() + mem::size_of::<usize>();
+// approximate memory taken by a version of a key besides its value bytes
+const VERSION_OVERHEAD: usize = mem::size_of::<(u64, MemValue)>();
+
+fn key_usage(key: &[u8]) -> usize {
+    key.len() + KEY_OVERHEAD
+}
+
+fn version_usage(value: &MemValue) -> usize {
+    let len = match *value {
+        MemValue::Value(ref value) => value.len(),
+        MemValue::Delete => 0,
+    };
+    len + VERSION_OVERHEAD
+}
+
+// versions of a key along with their sequence numbers, ordered from oldest to newest
+type Versions = Vec<(u64, MemValue)>;
+
+// Sorted in-memory table.
+// Every write is tagged with a sequence number, and the older versions of a key are kept as long as a snapshot
+// may read them, so that the table can be read as of any sequence number that a live snapshot was taken at
 pub struct MemTable {
-    pub table: CHashMap<Vec<u8>, MemValue>,
+    table: RwLock<BTreeMap<Vec<u8>, Versions>>,
+    // approximate memory footprint of the entries, only updated while the table is write locked
+    memory_usage: AtomicUsize,
 }
 
 impl MemTable {
     pub fn new() -> MemTable {
         MemTable {
-            table: CHashMap::new(),
+            table: RwLock::new(BTreeMap::new()),
+            memory_usage: AtomicUsize::new(0),
         }
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<MemValue> {
-        self.table.get(key).map(|guard| (*guard).clone())
+    // returns the newest version of the key with a sequence number not greater than sequence
+    pub fn get(&self, key: &[u8], sequence: u64) -> Result<Option<MemValue>> {
+        let table = self.table.read()?;
+        Ok(table.get(key).and_then(|versions| visible(versions, sequence)))
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.table
-            .insert(key.to_vec(), MemValue::Value(value.to_vec()));
+    // applies all the operations at once, readers see either none or all of them.
+    // The operations are numbered from first_sequence, and the versions they replace are dropped unless they are
+    // visible to the latest snapshot
+    pub fn write(
+        &self,
+        ops: &[(Vec<u8>, MemValue)],
+        first_sequence: u64,
+        latest_snapshot: Option<u64>,
+    ) -> Result<()> {
+        let mut table = self.table.write()?;
+        let mut usage = self.memory_usage.load(Ordering::Relaxed);
+        for (i, (key, value)) in ops.iter().enumerate() {
+            let versions = table.entry(key.clone()).or_insert_with(|| {
+                usage += key_usage(key);
+                Vec::new()
+            });
+            versions.retain(|(sequence, old_value)| {
+                let keep = latest_snapshot.is_some_and(|snapshot| *sequence <= snapshot);
+                if !keep {
+                    usage -= version_usage(old_value);
+                }
+                keep
+            });
+            usage += version_usage(value);
+            versions.push((first_sequence + i as u64, value.clone()));
+        }
+        self.memory_usage.store(usage, Ordering::Relaxed);
         Ok(())
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<()> {
-        self.table.insert(key.to_vec(), MemValue::Delete);
-        Ok(())
+    // number of keys in the table
+    pub fn len(&self) -> usize {
+        self.table.read().map(|table| table.len()).unwrap_or(0)
     }
 
-    pub fn size(&self) -> usize {
-        self.table.len()
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    pub fn  is_empty(&self) -> bool {
-        self.table.is_empty()
+    // approximate memory footprint in bytes of the keys, values and per-entry overhead of the table
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
     }
 
-    pub fn clear(&self) -> MemTable {
-        MemTable {
-            table: self.table.clear(),
+    // returns a copy of the newest version of every key of the table, sorted by key
+    pub fn entries(&self) -> Result<Vec<(Vec<u8>, MemValue)>> {
+        self.range((Bound::Unbounded, Bound::Unbounded), u64::MAX)
+    }
+
+    // returns a copy of the newest version, with a sequence number not greater than sequence,
+    // of every key within the bounds, sorted by key
+    pub fn range(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        sequence: u64,
+    ) -> Result<Vec<(Vec<u8>, MemValue)>> {
+        // BTreeMap::range panics on a range that ends before it starts
+        let empty = match bounds {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+        if empty {
+            return Ok(Vec::new());
         }
+        let table = self.table.read()?;
+        Ok(table
+            .range::<[u8], _>(bounds)
+            .filter_map(|(key, versions)| Some((key.clone(), visible(versions, sequence)?)))
+            .collect())
+    }
+}
+
+// returns the newest of the versions with a sequence number not greater than sequence
+fn visible(versions: &[(u64, MemValue)], sequence: u64) -> Option<MemValue> {
+    versions
+        .iter()
+        .rev()
+        .find(|(version_sequence, _)| *version_sequence <= sequence)
+        .map(|(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(len: usize) -> MemValue {
+        MemValue::Value(vec![0; len])
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mem_table = MemTable::new();
+        mem_table.write(&[(b"key".to_vec(), value(1000))], 1, None).unwrap();
+        assert_eq!(mem_table.memory_usage(), 3 + KEY_OVERHEAD + 1000 + VERSION_OVERHEAD);
+
+        // overwriting a key replaces the size of its old value
+        mem_table.write(&[(b"key".to_vec(), value(10))], 2, None).unwrap();
+        assert_eq!(mem_table.memory_usage(), 3 + KEY_OVERHEAD + 10 + VERSION_OVERHEAD);
+
+        mem_table
+            .write(
+                &[
+                    (b"key".to_vec(), MemValue::Delete),
+                    (b"other".to_vec(), MemValue::Delete),
+                ],
+                3,
+                None,
+            )
+            .unwrap();
+        assert_eq!(mem_table.memory_usage(), 3 + 5 + 2 * (KEY_OVERHEAD + VERSION_OVERHEAD));
+    }
+
+    #[test]
+    fn test_versions() {
+        let mem_table = MemTable::new();
+        mem_table.write(&[(b"key".to_vec(), value(1))], 1, None).unwrap();
+        mem_table.write(&[(b"key".to_vec(), value(2))], 2, None).unwrap();
+        // the version written at 2 is kept for a snapshot taken at 2
+        mem_table.write(&[(b"key".to_vec(), MemValue::Delete)], 3, Some(2)).unwrap();
+        mem_table.write(&[(b"new".to_vec(), value(3))], 4, Some(2)).unwrap();
+
+        let len = |value: Option<MemValue>| match value {
+            Some(MemValue::Value(value)) => Some(value.len()),
+            Some(MemValue::Delete) => Some(0),
+            None => None,
+        };
+        assert_eq!(len(mem_table.get(b"key", 1).unwrap()), None);
+        assert_eq!(len(mem_table.get(b"key", 2).unwrap()), Some(2));
+        assert_eq!(len(mem_table.get(b"key", u64::MAX).unwrap()), Some(0));
+        assert_eq!(mem_table.range((Bound::Unbounded, Bound::Unbounded), 2).unwrap().len(), 1);
+        assert_eq!(mem_table.entries().unwrap().len(), 2);
     }
 }
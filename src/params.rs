@@ -1,11 +1,62 @@
-#[derive(Default)]
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::compaction::CompactionFilter;
+
+#[derive(Clone, Default)]
 pub struct DBParams {
     /// create a new db while opening if it doesn't exist
     pub create_if_missing: bool,
-    /// size of the mem_table after which it is flushed to disk
+    /// approximate size in bytes of the keys, values and per-entry overhead of the mem_table after which it is flushed to disk
     pub write_buffer_size: usize,
-    /// size of cache
+    /// maximum number of immutable mem_tables waiting to be flushed, writes stall while the queue is full
+    pub max_flush_tables: usize,
+    /// capacity in bytes of the cache of log tables, which keeps the sparse index and the bloom filter of the
+    /// most recently used tables in memory. Every index holds one key per block of the table
     pub cache_size: usize,
+    /// maximum number of data tables kept open by the cache of log tables, the least recently used tables are
+    /// evicted from the cache, closing their files, when the limit is reached. Tables pinned by snapshots and
+    /// iterators keep their files open until they are released
+    pub max_open_files: usize,
+    /// whether the files of the log tables are mapped into memory instead of read with positional reads,
+    /// which saves a system call for every block read. Lookups search the mapped blocks in place, bypassing the
+    /// block cache, so only the value found is copied, while iterators still decode the blocks they read
+    pub mmap_reads: bool,
+    /// capacity in bytes of the cache of data blocks shared by all the log tables, 0 disables the cache.
+    /// Blocks are charged the memory they take up once decoded, which exceeds their size on disk
+    pub block_cache_size: usize,
+    /// number of bits per key of the bloom filter built for every log table, 0 disables the filters.
+    /// With 10 bits per key about 1% of the lookups of a missing key read the index of a table
+    pub bloom_bits_per_key: usize,
+    /// when writes are synced to the disk
+    pub sync_policy: SyncPolicy,
+    /// merge the log tables in a background thread, dropping the overwritten values and deleted keys
+    pub auto_compaction: bool,
+    /// how compactions pick the log tables they merge. The strategy is recorded in the db when it is created,
+    /// and the db keeps using it when reopened whatever the strategy given here
+    pub compaction_strategy: CompactionStrategy,
+    /// number of levels of log tables. Level 0 holds the tables flushed from mem_tables, every deeper level holds
+    /// tables with disjoint key ranges, and the last level has no size limit
+    pub num_levels: usize,
+    /// number of log tables in level 0 that triggers a compaction into level 1, or with size-tiered compaction
+    /// number of sorted runs that triggers a compaction
+    pub level0_compaction_trigger: usize,
+    /// number of log tables in level 0 at which the writes that fill the mem_table wait for a compaction before
+    /// it is queued for flushing
+    pub level0_stop_writes_trigger: usize,
+    /// size in bytes of level 1 above which it is compacted into level 2
+    pub max_bytes_for_level_base: u64,
+    /// growth factor of the size of every level below 1 over the level above it
+    pub max_bytes_for_level_multiplier: u64,
+    /// with size-tiered compaction, percentage by which a sorted run may be larger than the total size of the
+    /// newer runs merged before it and still be merged with them
+    pub size_tiered_size_ratio: u64,
+    /// with size-tiered compaction, minimum number of sorted runs of similar size merged together
+    pub size_tiered_min_merge_width: usize,
+    /// filter called by compactions for every entry they merge, which can remove the entry or replace its value
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// approximate size in bytes of the data tables written by compactions
+    pub target_table_size: usize,
 }
 
 impl DBParams {
@@ -13,8 +64,91 @@ impl DBParams {
     pub fn new() -> DBParams {
         DBParams {
             create_if_missing: true,
-            write_buffer_size: 1 << 12,
-            cache_size: 1 << 12,
+            write_buffer_size: 4 << 20,
+            max_flush_tables: 4,
+            cache_size: 64 << 20,
+            max_open_files: 1000,
+            mmap_reads: false,
+            block_cache_size: 8 << 20,
+            bloom_bits_per_key: 10,
+            sync_policy: SyncPolicy::NoSync,
+            auto_compaction: true,
+            compaction_strategy: CompactionStrategy::Leveled,
+            num_levels: 7,
+            level0_compaction_trigger: 4,
+            level0_stop_writes_trigger: 12,
+            max_bytes_for_level_base: 10 << 20,
+            max_bytes_for_level_multiplier: 10,
+            size_tiered_size_ratio: 1,
+            size_tiered_min_merge_width: 2,
+            compaction_filter: None,
+            target_table_size: 2 << 20,
+        }
+    }
+}
+
+/// Policy used by compactions to pick the log tables they merge
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CompactionStrategy {
+    /// every level below 0 is a single sorted run limited to a size that grows exponentially with its depth,
+    /// and is merged into the next level a table at a time. Reads probe few tables, but data is rewritten
+    /// about once per level for every level it moves down
+    #[default]
+    Leveled,
+    /// every table of level 0 and every level below it is a sorted run, and runs of similar size are merged
+    /// together. Data is rewritten less often, but reads probe more runs and overwritten values hold on to
+    /// space longer. The number of sorted runs below level 0 is bounded by the number of levels
+    SizeTiered,
+}
+
+/// Durability policy of the db.
+/// With any policy other than NoSync the log tables, the MANIFEST file and the directory entries
+/// are synced when a mem_table is flushed, before its write-ahead log is removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SyncPolicy {
+    /// never sync, writes survive a crash of the process but not of the machine
+    #[default]
+    NoSync,
+    /// sync the write-ahead log after every write
+    EveryWrite,
+    /// sync the write-ahead log once for every given number of writes, a write batch counts as one write
+    Batch(usize),
+    /// sync the write-ahead log from a background thread at the given interval
+    Periodic(Duration),
+}
+
+/// Options of a single write
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// sync the write-ahead log before returning, irrespective of the sync_policy of the db
+    pub sync: bool,
+}
+
+impl WriteOptions {
+    /// Returns a WriteOptions object with default values
+    pub fn new() -> WriteOptions {
+        WriteOptions { sync: false }
+    }
+}
+
+/// Options of a single read
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOptions {
+    /// verify the checksums of the index entries and data records read from log tables
+    pub verify_checksums: bool,
+}
+
+impl ReadOptions {
+    /// Returns a ReadOptions object with default values
+    pub fn new() -> ReadOptions {
+        ReadOptions {
+            verify_checksums: true,
         }
     }
 }
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions::new()
+    }
+}
@@ -0,0 +1,184 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use crate::errors::{Error, Result};
+use crate::lock;
+use crate::manifest::{self, Manifest, TableMeta};
+use crate::memtable::MemTable;
+use crate::params::DBParams;
+use crate::table::{Table, TableBuilder};
+use crate::wal;
+
+/// Outcome of repairing a database
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// log tables that were validated and kept
+    pub recovered_tables: Vec<u64>,
+    /// write-ahead logs that were converted to log tables
+    pub recovered_logs: Vec<u64>,
+    /// log tables that failed validation and were moved to the lost/ directory
+    pub lost_tables: Vec<u64>,
+}
+
+// Rebuilds the manifest of the db from the table files found in its directory.
+// Write-ahead logs are converted to log tables of level 0 with fresh file numbers, allocated in the order of the logs
+// above every file number in use, as when the db recovers its logs on open. A log is allocated its number before the
+// previous mem_table is flushed, so its own number may be smaller than those of tables holding older data.
+// If the manifest can still be read, the tables keep their level and the tables it does not record, which are
+// left behind by flushes and compactions interrupted before their tables were added, are not part of the db.
+// The logs it records as flushed are not replayed either. The compaction strategy recorded in the manifest is kept
+// as well, otherwise the db is next opened with the strategy of its DBParams.
+// Otherwise every table is placed in the level it was written to, see place_tables.
+pub fn repair(db_name: &str) -> Result<RepairReport> {
+    if !Path::new(db_name).is_dir() {
+        return Err(Error::DBNameInvalidError);
+    }
+    let _lock_file = lock::lock_db(db_name)?;
+
+    let mut report = RepairReport::default();
+    let recorded = Manifest::read(db_name).ok();
+    let mut tables = BTreeSet::new();
+    let mut logs = Vec::new();
+    let mut max_file_no = 0;
+    for entry in fs::read_dir(db_name)? {
+        let file_name = entry?.file_name();
+        if let Some((file_no, file_type)) = manifest::parse_file_name(&file_name.to_string_lossy()) {
+            match file_type {
+                "dt" | "ix" => {
+                    let is_recorded = recorded
+                        .as_ref()
+                        .is_none_or(|recorded| recorded.live_tables().any(|table| table.file_no == file_no));
+                    if is_recorded {
+                        tables.insert(file_no);
+                    }
+                }
+                "wal" => logs.push(file_no),
+                _ => (),
+            };
+            max_file_no = max_file_no.max(file_no);
+        }
+    }
+    logs.sort_unstable();
+
+    let flushed_logs = recorded.as_ref().map_or(0, |recorded| recorded.log_no());
+    for log_no in logs {
+        // the log was flushed to a table before the flush was interrupted, and its keys may have been
+        // overwritten since
+        if log_no < flushed_logs {
+            fs::remove_file(wal::log_file_name(db_name, log_no))?;
+            continue;
+        }
+        let mem_table = MemTable::new();
+        wal::replay(db_name, log_no, &mem_table, 0)?;
+        if !mem_table.is_empty() {
+            max_file_no += 1;
+            let mut table_builder = TableBuilder::new(db_name, max_file_no, 0, DBParams::new().bloom_bits_per_key);
+            for (key, value) in mem_table.entries()? {
+                table_builder.add(&key, &value)?;
+            }
+            table_builder.flush(true)?;
+            tables.insert(max_file_no);
+            report.recovered_logs.push(log_no);
+        }
+        fs::remove_file(wal::log_file_name(db_name, log_no))?;
+    }
+
+    let mut metas = Vec::new();
+    for file_no in tables {
+        let level = recorded
+            .iter()
+            .flat_map(|recorded| recorded.live_tables())
+            .find(|table| table.file_no == file_no)
+            .map(|table| table.level);
+        match validate_table(db_name, file_no, level) {
+            Ok(Some(meta)) => {
+                metas.push(meta);
+                report.recovered_tables.push(file_no);
+            }
+            // empty tables hold nothing to recover
+            Ok(None) => (),
+            Err(_) => {
+                quarantine_table(db_name, file_no)?;
+                report.lost_tables.push(file_no);
+            }
+        }
+    }
+
+    if recorded.is_none() {
+        metas = place_tables(metas);
+    }
+
+    let compaction_strategy = recorded.and_then(|recorded| recorded.compaction_strategy());
+    Manifest::rebuild(db_name, &metas, compaction_strategy, max_file_no + 1, true)?;
+    Ok(report)
+}
+
+// Checks that both files of the table exist and that every index entry and data record, as well as the
+// filter of the table if it has one, is intact.
+// Returns the description of the table in the given level, or else in the level it was written to, or None if
+// the table is empty
+fn validate_table(db_name: &str, file_no: u64, level: Option<usize>) -> Result<Option<TableMeta>> {
+    // opening the table also reads its filter
+    let table = Table::open(db_name, file_no, None, false, true)?;
+    table.verify()?;
+    let index_len = fs::metadata(format!("{}/{}.ix", db_name, file_no))?.len();
+    Ok(table.key_range(true)?.map(|(smallest, largest)| TableMeta {
+        file_no,
+        level: level.unwrap_or_else(|| table.level()),
+        size: table.data_len() + index_len,
+        smallest,
+        largest,
+    }))
+}
+
+// Places the tables, described with the level they were written to, in the levels of the rebuilt manifest.
+// The tables of a level deeper than 0 overlap each other when a compaction into that level was interrupted
+// before its inputs were removed. Its outputs, which have greater file numbers than the tables of the level
+// they were merged with, then stay in the level, and the tables they overlap are moved to a level of their own
+// right below, since they hold older data than the outputs but newer data than the deeper levels.
+// The deeper levels are shifted down as needed
+fn place_tables(tables: Vec<TableMeta>) -> Vec<TableMeta> {
+    let mut levels: BTreeMap<usize, Vec<TableMeta>> = BTreeMap::new();
+    for table in tables {
+        levels.entry(table.level).or_default().push(table);
+    }
+
+    let mut placed = levels.remove(&0).unwrap_or_default();
+    let mut next_level = 1;
+    for (level, mut tables) in levels {
+        tables.sort_unstable_by_key(|table| Reverse(table.file_no));
+        next_level = next_level.max(level);
+        while !tables.is_empty() {
+            let mut run: Vec<TableMeta> = Vec::new();
+            let mut overlapped = Vec::new();
+            for mut table in tables {
+                if run.iter().any(|other| other.overlaps(Some(&table.smallest), Some(&table.largest))) {
+                    overlapped.push(table);
+                } else {
+                    table.level = next_level;
+                    run.push(table);
+                }
+            }
+            placed.extend(run);
+            tables = overlapped;
+            next_level += 1;
+        }
+    }
+    placed
+}
+
+// moves the files of the table to the lost/ directory
+fn quarantine_table(db_name: &str, file_no: u64) -> Result<()> {
+    let lost_dir = format!("{}/lost", db_name);
+    fs::create_dir_all(&lost_dir)?;
+    for file_type in ["ix", "dt", "bf"].iter() {
+        let file_name = format!("{}.{}", file_no, file_type);
+        let path = format!("{}/{}", db_name, file_name);
+        if Path::new(&path).exists() {
+            fs::rename(path, format!("{}/{}", lost_dir, file_name))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex};
+
+use crate::errors::Result;
+use crate::filter::FilterStats;
+use crate::iterator::{self, DBIterator, DBReverseIterator, Source};
+use crate::manifest::TableMeta;
+use crate::memtable::{MemTable, MemValue};
+use crate::params::ReadOptions;
+use crate::table::{Table, TableValue};
+
+/// A consistent point-in-time view of the db.
+/// Reads through a snapshot see the db exactly as it was when the snapshot was taken, regardless of
+/// the writes and flushes that happen afterwards.
+/// The snapshot pins the mem_tables and log tables it reads until it is dropped
+pub struct Snapshot {
+    // sequence number of the last write visible to the snapshot
+    sequence: u64,
+    // mem_table and flush_tables from newest to oldest
+    mem_tables: Vec<Arc<MemTable>>,
+    // log tables from newest to oldest, along with their key ranges
+    tables: Vec<(TableMeta, Arc<Table>)>,
+    // sequence numbers of the live snapshots of the db, along with the number of snapshots taken at each
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+    // bloom filter counters of the db
+    filter_stats: Arc<FilterStats>,
+}
+
+impl Snapshot {
+    // registers a snapshot taken at sequence, which is released when it is dropped
+    pub(crate) fn new(
+        sequence: u64,
+        mem_tables: Vec<Arc<MemTable>>,
+        snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+        filter_stats: Arc<FilterStats>,
+    ) -> Result<Snapshot> {
+        *(snapshots.lock()?).entry(sequence).or_insert(0) += 1;
+        Ok(Snapshot {
+            sequence,
+            mem_tables,
+            tables: Vec::new(),
+            snapshots,
+            filter_stats,
+        })
+    }
+
+    // pins the log tables of the snapshot, ordered from newest to oldest
+    pub(crate) fn pin_tables(&mut self, tables: Vec<(TableMeta, Arc<Table>)>) {
+        self.tables = tables;
+    }
+
+    /// Returns the sequence number of the last write visible to the snapshot
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the value corresponding to the key as of the snapshot
+    pub fn get<S: AsRef<[u8]>>(&self, key: S) -> Result<Option<Vec<u8>>> {
+        self.get_with_options(key, &ReadOptions::new())
+    }
+
+    /// Returns the value corresponding to the key as of the snapshot using the given read options
+    pub fn get_with_options<S: AsRef<[u8]>>(
+        &self,
+        key: S,
+        read_options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>> {
+        let key_bytes = key.as_ref();
+        for mem_table in self.mem_tables.iter() {
+            get_mem_value!(mem_table.get(key_bytes, self.sequence)?);
+        }
+        // as with DB::get, only the tables whose key range contains the key are probed, and a block of the table
+        // is only read if its filter may contain the key
+        for (meta, table) in self.tables.iter() {
+            if !meta.overlaps(Some(key_bytes), Some(key_bytes)) {
+                continue;
+            }
+            let value = table.get_filtered(key_bytes, read_options.verify_checksums, &self.filter_stats)?;
+            get_mem_value!(value.map(TableValue::into_mem_value));
+        }
+        Ok(None)
+    }
+
+    /// Returns an iterator over all the key-value pairs of the snapshot in increasing order of keys
+    pub fn iter(&self) -> Result<DBIterator> {
+        self.iter_with_options(&ReadOptions::new())
+    }
+
+    /// Returns an iterator over all the key-value pairs of the snapshot in increasing order of keys
+    /// using the given read options
+    pub fn iter_with_options(&self, read_options: &ReadOptions) -> Result<DBIterator> {
+        self.bounded_iter(Bound::Unbounded, Bound::Unbounded, read_options)
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot with keys within the range, in increasing order of keys
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Result<DBIterator> {
+        self.range_with_options(range, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot with keys within the range, in increasing order of keys
+    /// using the given read options
+    pub fn range_with_options<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        read_options: &ReadOptions,
+    ) -> Result<DBIterator> {
+        let to_vec = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+        self.bounded_iter(
+            to_vec(range.start_bound()),
+            to_vec(range.end_bound()),
+            read_options,
+        )
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot with keys within the range, in decreasing order of keys
+    pub fn range_rev<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Result<DBReverseIterator> {
+        self.range_rev_with_options(range, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot with keys within the range, in decreasing order of keys
+    /// using the given read options
+    pub fn range_rev_with_options<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        read_options: &ReadOptions,
+    ) -> Result<DBReverseIterator> {
+        Ok(DBReverseIterator::new(self.range_with_options(range, read_options)?))
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot with keys starting with prefix, in increasing order of keys
+    pub fn prefix_iter<K: AsRef<[u8]>>(&self, prefix: K) -> Result<DBIterator> {
+        self.prefix_iter_with_options(prefix, &ReadOptions::new())
+    }
+
+    /// Returns an iterator over the key-value pairs of the snapshot with keys starting with prefix, in increasing order of keys
+    /// using the given read options
+    pub fn prefix_iter_with_options<K: AsRef<[u8]>>(
+        &self,
+        prefix: K,
+        read_options: &ReadOptions,
+    ) -> Result<DBIterator> {
+        let prefix = prefix.as_ref();
+        self.bounded_iter(
+            Bound::Included(prefix.to_vec()),
+            iterator::prefix_end(prefix),
+            read_options,
+        )
+    }
+
+    // the sources of the iterator are ordered from newest to oldest, in the same order as get probes them
+    fn bounded_iter(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        read_options: &ReadOptions,
+    ) -> Result<DBIterator> {
+        let bounds = (
+            start.as_ref().map(Vec::as_slice),
+            end.as_ref().map(Vec::as_slice),
+        );
+        let mut sources = Vec::with_capacity(self.mem_tables.len() + self.tables.len());
+        for mem_table in self.mem_tables.iter() {
+            sources.push(Source::Mem(mem_table.range(bounds, self.sequence)?));
+        }
+        for (_, table) in self.tables.iter() {
+            sources.push(Source::Table(table.clone()));
+        }
+        Ok(DBIterator::new(
+            sources,
+            start,
+            end,
+            read_options.verify_checksums,
+        ))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Ok(mut snapshots) = self.snapshots.lock() {
+            if let Some(count) = snapshots.get_mut(&self.sequence) {
+                *count -= 1;
+                if *count == 0 {
+                    snapshots.remove(&self.sequence);
+                }
+            }
+        }
+    }
+}
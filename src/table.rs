@@ -1,83 +1,168 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::Mmap;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::io::Cursor;
-use std::io::SeekFrom;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::errors::{Error, Result};
+use crate::cache::ShardedLRUCache;
+use crate::errors::{Corruption, Error, Result};
+use crate::filter::{self, BloomFilter, FilterStats};
+use crate::manifest::TableMeta;
 use crate::memtable::MemValue;
 
+// approximate size of the data blocks of a table, a block is closed as soon as it reaches this size
+pub const BLOCK_SIZE: usize = 4096;
+
+// length of the header of the index table - <level><checksum>
+const INDEX_HEADER_LEN: usize = 12;
+
 // Table builder builds the on-disk log files
-// Corresponding to each memtable two log files - data table and index table - are generated
+// Corresponding to each memtable two log files - data table and index table - are generated.
+// The data table is a sequence of blocks of sorted entries, and the index table holds the first key of every block
+// along with its position in the data table, so only the index has to be kept in memory and a lookup reads one block.
+// The index table starts with the level the table is written to, which lets a repair place the table without the
+// manifest. Keys must be added in increasing order.
+// Unless bits_per_key is 0, a bloom filter over the keys is written to the filter file of the table
 pub struct TableBuilder {
     db_name: String,
     file_no: u64,
+    level: usize,
+    bits_per_key: usize,
+    key_hashes: Vec<u64>,
     data: Vec<u8>,
     index: Vec<u8>,
-    offset: u64,
+    // entries of the block being built, along with its first key
+    block: Vec<u8>,
+    first_key: Option<Vec<u8>>,
+    // first and last keys added to the table
+    smallest: Option<Vec<u8>>,
+    last_key: Option<Vec<u8>>,
 }
 
 impl TableBuilder {
-    pub fn new(db_name: &str, file_no: u64) -> TableBuilder {
+    pub fn new(db_name: &str, file_no: u64, level: usize, bits_per_key: usize) -> TableBuilder {
         TableBuilder {
             db_name: String::from(db_name),
             file_no,
+            level,
+            bits_per_key,
+            key_hashes: Vec::new(),
             data: Vec::new(),
             index: Vec::new(),
-            offset: 0,
+            block: Vec::new(),
+            first_key: None,
+            smallest: None,
+            last_key: None,
         }
     }
 
-    // Adds the key and value marker (delete or data offset) to the index table and actual value to the data table
+    // Adds the key, value marker and value (if not deleted) to the current block of the data table
     pub fn add(&mut self, key: &[u8], value: &MemValue) -> Result<()> {
-        self.index.append(&mut self.encode(key)?);
-        self.index.push(value.encode());
-        match value {
-            MemValue::Value(val) => {
-                let mut data_offset = Vec::with_capacity(8);
-                data_offset.write_u64::<BigEndian>(self.offset)?;
-                self.index.append(&mut data_offset);
-                self.data.append(&mut self.encode(&val)?);
-                self.offset += 8 + val.len() as u64;
-            }
-            MemValue::Delete => (),
+        debug_assert!(
+            self.last_key.as_ref().is_none_or(|last_key| last_key.as_slice() < key),
+            "keys added out of order"
+        );
+        self.last_key = Some(key.to_vec());
+        if self.bits_per_key > 0 {
+            self.key_hashes.push(filter::hash(key));
+        }
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_vec());
+        }
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_vec());
+        }
+
+        encode(&mut self.block, key)?;
+        self.block.push(value.encode());
+        if let MemValue::Value(val) = value {
+            encode(&mut self.block, val)?;
+        }
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
         }
         Ok(())
     }
 
-    // encodes data to on-disk format - <data len><data bytes>
-    fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut vec = Vec::with_capacity(8 + data.len());
-        vec.write_u64::<BigEndian>(data.len() as u64)?;
-        vec.extend_from_slice(data);
-        Ok(vec)
+    // appends the current block, followed by its checksum, to the data table and adds its index entry
+    // <first key len><first key bytes><block offset><block len><checksum> to the index table
+    fn finish_block(&mut self) -> Result<()> {
+        let first_key = match self.first_key.take() {
+            Some(first_key) => first_key,
+            None => return Ok(()),
+        };
+        self.block.write_u32::<BigEndian>(crc32c::crc32c(&self.block))?;
+
+        let mut entry = Vec::with_capacity(first_key.len() + 28);
+        encode(&mut entry, &first_key)?;
+        entry.write_u64::<BigEndian>(self.data.len() as u64)?;
+        entry.write_u64::<BigEndian>(self.block.len() as u64)?;
+        entry.write_u32::<BigEndian>(crc32c::crc32c(&entry))?;
+        self.index.append(&mut entry);
+        self.data.append(&mut self.block);
+        Ok(())
     }
 
-    // flushes to log files in the disk
-    pub fn flush(&mut self) -> Result<()> {
-        File::create(format!("{}/{}.dt", self.db_name, self.file_no))?.write_all(&self.data)?;
-        File::create(format!("{}/{}.ix", self.db_name, self.file_no))?.write_all(&self.index)?;
+    // approximate size in bytes of the data table built so far
+    pub fn data_size(&self) -> usize {
+        self.data.len() + self.block.len()
+    }
+
+    // Flushes to log files in the disk, syncing them if asked to.
+    // Returns the description of the table
+    pub fn flush(&mut self, sync: bool) -> Result<TableMeta> {
+        self.finish_block()?;
+        let mut data_file = File::create(format!("{}/{}.dt", self.db_name, self.file_no))?;
+        data_file.write_all(&self.data)?;
+        let mut header = Vec::with_capacity(INDEX_HEADER_LEN);
+        header.write_u64::<BigEndian>(self.level as u64)?;
+        header.write_u32::<BigEndian>(crc32c::crc32c(&header))?;
+        let mut index_file = File::create(format!("{}/{}.ix", self.db_name, self.file_no))?;
+        index_file.write_all(&header)?;
+        index_file.write_all(&self.index)?;
+        if sync {
+            data_file.sync_all()?;
+            index_file.sync_all()?;
+        }
+        if self.bits_per_key > 0 {
+            BloomFilter::build(&self.key_hashes, self.bits_per_key).write(&self.db_name, self.file_no, sync)?;
+        }
+
+        let meta = TableMeta {
+            file_no: self.file_no,
+            level: self.level,
+            size: (INDEX_HEADER_LEN + self.data.len() + self.index.len()) as u64,
+            smallest: self.smallest.take().unwrap_or_default(),
+            largest: self.last_key.take().unwrap_or_default(),
+        };
 
         // reset data after flush
         self.data.clear();
         self.index.clear();
-        self.offset = 0;
-        self.file_no += 1;
-        Ok(())
+        self.key_hashes.clear();
+        Ok(meta)
     }
+}
 
-    pub fn file_no(&self) -> u64 {
-        self.file_no
-    }
+// encodes data to on-disk format - <data len><data bytes>
+fn encode(buf: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    buf.write_u64::<BigEndian>(data.len() as u64)?;
+    buf.extend_from_slice(data);
+    Ok(())
 }
 
-// Delete marker or offset to the data table
-enum IndexValue {
-    Offset(u64),
-    Delete,
+// Position of a block in the data table, along with the first key of the block
+pub struct IndexEntry {
+    pub first_key: Vec<u8>,
+    pub offset: u64,
+    pub len: u64,
 }
 
 #[repr(u8)]
@@ -87,84 +172,710 @@ enum MemValueCode {
     Delete = 1u8,
 }
 
-// In-memory Index table corresponding to a particular log file
+// Decoded entries of a data block, sorted by key
+pub type Block = Vec<(Vec<u8>, MemValue)>;
+
+// Value of a key in a log table. Looked up in a memory mapped data table, the value is a slice of the mapping,
+// which lives as long as the table, otherwise it is copied out of the decoded block
+#[derive(Debug, PartialEq)]
+pub enum TableValue<'a> {
+    Value(Cow<'a, [u8]>),
+    Delete,
+}
+
+impl TableValue<'_> {
+    pub fn into_mem_value(self) -> MemValue {
+        match self {
+            TableValue::Value(value) => MemValue::Value(value.into_owned()),
+            TableValue::Delete => MemValue::Delete,
+        }
+    }
+}
+
+// Cache of decoded data blocks shared by the log tables of a db, keyed by the file number and offset of the block.
+// The capacity bounds the total size of the cached blocks in bytes. File numbers are never reused, so the blocks
+// of a table never have to be invalidated and simply age out of the cache once the table is no longer read
+pub struct BlockCache {
+    blocks: ShardedLRUCache<(u64, u64), Arc<Block>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            blocks: ShardedLRUCache::new(capacity),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn get(&self, file_no: u64, offset: u64) -> Result<Option<Arc<Block>>> {
+        let block = self.blocks.get(&(file_no, offset))?;
+        match block {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(block)
+    }
+
+    fn insert(&self, file_no: u64, offset: u64, block: Arc<Block>, charge: usize) -> Result<()> {
+        self.blocks.put_with_charge((file_no, offset), block, charge)
+    }
+
+    // total memory taken up by the cached blocks in bytes
+    pub fn usage(&self) -> Result<usize> {
+        self.blocks.usage()
+    }
+
+    // number of block reads served from the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    // number of block reads that had to read the data table
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+// Data table of an open log table
+enum DataFile {
+    // read with positional reads
+    File(File),
+    // mapped into memory, lookups search the blocks in place and return slices of the mapping, while iterators
+    // decode the blocks from the mapping without reading them into a buffer first. Table files are never modified once written, and an unlinked file stays mapped until the table is dropped
+    Mmap(Mmap),
+}
+
+// In-memory sparse index of a particular log file, along with the open data table.
+// Blocks are read with positional reads or from a memory mapping, so any number of threads can read
+// the same table concurrently
 pub struct Table {
-    db_name: String,
     // log number of the index table
     file_no: u64,
-    // index maps key to an IndexValue, which is either a delete marker or an offset to corresponding data table
-    index: HashMap<Vec<u8>, IndexValue>,
+    // level the table was written to
+    level: usize,
+    data_file: DataFile,
+    data_len: u64,
+    // one entry per block of the data table, sorted by key
+    index: Vec<IndexEntry>,
+    // cache that the blocks read from the data table are added to, if any
+    block_cache: Option<Arc<BlockCache>>,
+    // bloom filter over the keys of the table, if it has one. It is loaded along with the index, so that the
+    // filters in memory are bounded by the capacity of the cache of log tables
+    filter: Option<BloomFilter>,
 }
 
 impl Table {
-    // loads the index table into memory
-    pub fn open(db_name: &str, file_no: u64) -> Result<Table> {
-        let mut f = File::open(format!("{}/{}.ix", db_name, file_no))?;
-        let mut index_buf = Vec::new();
-        f.read_to_end(&mut index_buf)?;
-        let mut index = HashMap::new();
-        let mut i = 0;
-        let buf_len = index_buf.len();
-        loop {
-            if i >= buf_len {
-                break;
+    // Loads the index table and the filter of the table into memory, verifying the checksum of every index entry
+    // if asked to, and opens the data table.
+    // With use_mmap both files are read through memory mappings
+    pub fn open(
+        db_name: &str,
+        file_no: u64,
+        block_cache: Option<Arc<BlockCache>>,
+        use_mmap: bool,
+        verify_checksums: bool,
+    ) -> Result<Table> {
+        let index_file = File::open(format!("{}/{}.ix", db_name, file_no))?;
+        let (level, index) = match map_file(&index_file, use_mmap)? {
+            Some(index_map) => decode_index(&index_map, file_no, verify_checksums)?,
+            None => {
+                let mut index_buf = Vec::new();
+                (&index_file).read_to_end(&mut index_buf)?;
+                decode_index(&index_buf, file_no, verify_checksums)?
             }
-            let key_size = Cursor::new(&index_buf[i..i + 8]).read_u64::<BigEndian>()?;
-            let mut key = vec![0; key_size as usize];
-
-            key.copy_from_slice(&index_buf[i + 8..i + 8 + key_size as usize]);
-            i += 8 + key_size as usize;
-
-            match FromPrimitive::from_u8(index_buf[i]) {
-                Some(MemValueCode::Value) => {
-                    i += 1;
-                    let offset = Cursor::new(&index_buf[i..i + 8]).read_u64::<BigEndian>()?;
-                    i += 8;
-                    index.insert(key, IndexValue::Offset(offset));
-                }
-                Some(MemValueCode::Delete) => {
-                    i += 1;
-                    index.insert(key, IndexValue::Delete);
-                }
-                None => return Err(Error::DBCorruptionError),
-            };
-        }
+        };
+        let data_file = File::open(format!("{}/{}.dt", db_name, file_no))?;
+        let data_len = data_file.metadata()?.len();
+        let data_file = match map_file(&data_file, use_mmap)? {
+            Some(data_map) => DataFile::Mmap(data_map),
+            None => DataFile::File(data_file),
+        };
+        let filter = BloomFilter::read(db_name, file_no)?;
         Ok(Table {
-            db_name: String::from(db_name),
             file_no,
+            level,
+            data_file,
+            data_len,
             index,
+            block_cache,
+            filter,
         })
     }
 
-    // Returns the value corresponding to the key from the data table
-    // Either the key corresponds to a delete marker (meaning the key is deleted)
-    // or it corresponds to data table offset in which case the value is returned
-    pub fn get(&self, key: &[u8]) -> Result<Option<MemValue>> {
-        let value = self.index.get(key);
-        match value {
-            Some(IndexValue::Offset(off)) => {
-                let mut f = File::open(format!("{}/{}.dt", self.db_name, self.file_no))?;
-                f.seek(SeekFrom::Start(*off))?;
-                let val = self.decode(&mut f)?;
-                Ok(Some(MemValue::Value(val)))
+    // checks the key against the bloom filter of the table, None if the table has no filter
+    pub fn may_contain(&self, key: &[u8]) -> Option<bool> {
+        self.filter.as_ref().map(|filter| filter.may_contain(key))
+    }
+
+    // Returns the value corresponding to the key like get, unless the bloom filter of the table rules the key out.
+    // The outcome of the filter check is counted in filter_stats
+    pub fn get_filtered(
+        &self,
+        key: &[u8],
+        verify_checksums: bool,
+        filter_stats: &FilterStats,
+    ) -> Result<Option<TableValue<'_>>> {
+        let may_contain = self.may_contain(key);
+        if may_contain == Some(false) {
+            filter_stats.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        let value = self.get(key, verify_checksums)?;
+        if value.is_none() && may_contain.is_some() {
+            filter_stats.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(value)
+    }
+
+    // Returns the value corresponding to the key from the data table, reading the only block that may contain it.
+    // Either the key corresponds to a delete marker (meaning the key is deleted) or to a value.
+    // A memory mapped block is searched in place without going through the block cache, and the value is
+    // returned as a slice of the mapping
+    pub fn get(&self, key: &[u8], verify_checksums: bool) -> Result<Option<TableValue<'_>>> {
+        if self.index.is_empty() || key < self.index[0].first_key.as_slice() {
+            return Ok(None);
+        }
+        let i = self.find_block(key);
+        if let DataFile::Mmap(ref data_map) = self.data_file {
+            let entry = &self.index[i];
+            let block = self.mapped_block(data_map, entry)?;
+            return find_in_block(block, key, self.file_no, entry.offset, verify_checksums);
+        }
+        let entries = self.read_block(i, verify_checksums)?;
+        match entries.binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key)) {
+            Ok(i) => Ok(Some(match entries[i].1 {
+                MemValue::Value(ref value) => TableValue::Value(Cow::Owned(value.clone())),
+                MemValue::Delete => TableValue::Delete,
+            })),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // number of blocks in the data table
+    pub fn num_blocks(&self) -> usize {
+        self.index.len()
+    }
+
+    // memory in bytes taken up by the index and the filter of the table, which the cache of log tables is charged
+    pub fn memory_usage(&self) -> usize {
+        let keys: usize = self.index.iter().map(|entry| entry.first_key.capacity()).sum();
+        mem::size_of::<Table>()
+            + self.index.capacity() * mem::size_of::<IndexEntry>()
+            + keys
+            + self.filter.as_ref().map_or(0, BloomFilter::memory_usage)
+    }
+
+    // returns the last block whose first key is not greater than key, or the first block if there is none
+    pub fn find_block(&self, key: &[u8]) -> usize {
+        self.index
+            .partition_point(|entry| entry.first_key.as_slice() <= key)
+            .saturating_sub(1)
+    }
+
+    // Returns the entries of the i-th block of the data table from the block cache, reading and decoding the block
+    // if it is not cached. The checksum of a block is only verified when it is read from the data table
+    pub fn read_block(&self, i: usize, verify_checksums: bool) -> Result<Arc<Block>> {
+        let entry = &self.index[i];
+        let block_cache = match self.block_cache {
+            Some(ref block_cache) => block_cache,
+            None => return Ok(Arc::new(self.load_block(entry, verify_checksums)?)),
+        };
+        if let Some(block) = block_cache.get(self.file_no, entry.offset)? {
+            return Ok(block);
+        }
+        let block = Arc::new(self.load_block(entry, verify_checksums)?);
+        block_cache.insert(self.file_no, entry.offset, block.clone(), block_charge(&block))?;
+        Ok(block)
+    }
+
+    // reads and decodes the entries of a block of the data table
+    fn load_block(&self, entry: &IndexEntry, verify_checksums: bool) -> Result<Block> {
+        match self.data_file {
+            DataFile::File(ref file) => {
+                self.check_bounds(entry)?;
+                let mut block = vec![0; entry.len as usize];
+                read_exact_at(file, &mut block, entry.offset)?;
+                decode_block(&block, self.file_no, entry.offset, verify_checksums)
             }
-            Some(IndexValue::Delete) => Ok(Some(MemValue::Delete)),
+            DataFile::Mmap(ref data_map) => {
+                let block = self.mapped_block(data_map, entry)?;
+                decode_block(block, self.file_no, entry.offset, verify_checksums)
+            }
+        }
+    }
+
+    // returns the bytes of a block of the memory mapped data table
+    fn mapped_block<'a>(&self, data_map: &'a Mmap, entry: &IndexEntry) -> Result<&'a [u8]> {
+        self.check_bounds(entry)?;
+        Ok(&data_map[entry.offset as usize..(entry.offset + entry.len) as usize])
+    }
+
+    // checks that the block of the index entry lies within the data table
+    fn check_bounds(&self, entry: &IndexEntry) -> Result<()> {
+        if entry.offset > self.data_len || entry.len > self.data_len - entry.offset {
+            return Err(corruption(self.file_no, "dt", entry.offset, Corruption::BadLength));
+        }
+        Ok(())
+    }
+
+    // returns the smallest and largest keys of the table, or None if the table is empty
+    pub fn key_range(&self, verify_checksums: bool) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let last_block = match self.index.len() {
+            0 => return Ok(None),
+            len => self.read_block(len - 1, verify_checksums)?,
+        };
+        match last_block.last() {
+            Some((largest, _)) => Ok(Some((self.index[0].first_key.clone(), largest.clone()))),
             None => Ok(None),
         }
     }
 
-    fn decode(&self, file: &mut File) -> Result<Vec<u8>> {
-        let mut size_buf = [0; 8];
-        file.read_exact(&mut size_buf)?;
-        let size = Cursor::new(size_buf).read_u64::<BigEndian>()?;
-        let mut content = vec![0; size as usize];
-        let mut bytes_read = 0;
-        loop {
-            let rb = file.read(&mut content[bytes_read..])?;
-            bytes_read += rb;
-            if bytes_read >= size as usize {
-                return Ok(content);
+    // size in bytes of the data table
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    // level the table was written to, which is the level it belongs to unless it was left behind by an
+    // interrupted compaction
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    // reads every block of the table, verifying its checksum
+    pub fn verify(&self) -> Result<()> {
+        for entry in self.index.iter() {
+            self.load_block(entry, true)?;
+        }
+        Ok(())
+    }
+}
+
+// maps the file into memory if asked to, empty files are never mapped
+fn map_file(file: &File, use_mmap: bool) -> Result<Option<Mmap>> {
+    if !use_mmap || file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    // safe as table files are never modified or truncated once written, and a corrupted mapping
+    // is caught by the checksums like any other read
+    let map = unsafe { Mmap::map(file)? };
+    Ok(Some(map))
+}
+
+// reads exactly buf.len() bytes at offset of the file without moving the cursor of the file
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Decodes the index table in buf - a header <level><checksum> followed by a sequence of
+// <first key len><first key bytes><block offset><block len><checksum>, into the level of the table and its entries.
+// Every length is validated against the remaining bytes, so a malformed index results in an error and never
+// in a panic or in an allocation larger than the buffer
+pub fn decode_index(buf: &[u8], file_no: u64, verify_checksums: bool) -> Result<(usize, Vec<IndexEntry>)> {
+    let header_error = |kind| corruption(file_no, "ix", 0, kind);
+    let level = read_u64(buf, 0).ok_or_else(|| header_error(Corruption::Truncated))?;
+    let checksum = read_u32(buf, 8).ok_or_else(|| header_error(Corruption::Truncated))?;
+    if verify_checksums && crc32c::crc32c(&buf[..8]) != checksum {
+        return Err(header_error(Corruption::ChecksumMismatch));
+    }
+
+    let mut index = Vec::new();
+    let mut i = INDEX_HEADER_LEN;
+    while i < buf.len() {
+        let entry_offset = i;
+        let error = move |kind| corruption(file_no, "ix", entry_offset as u64, kind);
+
+        let first_key = read_bytes(buf, &mut i).map_err(error)?;
+        let offset = read_u64(buf, i).ok_or_else(|| error(Corruption::Truncated))?;
+        let len = read_u64(buf, i + 8).ok_or_else(|| error(Corruption::Truncated))?;
+        i += 16;
+
+        let checksum = read_u32(buf, i).ok_or_else(|| error(Corruption::Truncated))?;
+        if verify_checksums && crc32c::crc32c(&buf[entry_offset..i]) != checksum {
+            return Err(error(Corruption::ChecksumMismatch));
+        }
+        i += 4;
+        index.push(IndexEntry {
+            first_key: first_key.to_vec(),
+            offset,
+            len,
+        });
+    }
+    Ok((level as usize, index))
+}
+
+// Decodes the data block in buf - a sequence of <key len><key bytes><value marker>[<value len><value bytes>]
+// followed by a checksum of the entries.
+// offset is the offset of the block in the data table, used for reporting corruption
+pub fn decode_block(
+    buf: &[u8],
+    file_no: u64,
+    offset: u64,
+    verify_checksums: bool,
+) -> Result<Block> {
+    let error = |kind| corruption(file_no, "dt", offset, kind);
+    let entries_buf = block_entries(buf, file_no, offset, verify_checksums)?;
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < entries_buf.len() {
+        let (key, value) = read_entry(entries_buf, &mut i).map_err(error)?;
+        entries.push((key.to_vec(), value.into_mem_value()));
+    }
+    Ok(entries)
+}
+
+// Looks the key up in the data block in buf without decoding the other entries of the block.
+// The value found is a slice of buf
+fn find_in_block<'a>(
+    buf: &'a [u8],
+    key: &[u8],
+    file_no: u64,
+    offset: u64,
+    verify_checksums: bool,
+) -> Result<Option<TableValue<'a>>> {
+    let error = |kind| corruption(file_no, "dt", offset, kind);
+    let entries_buf = block_entries(buf, file_no, offset, verify_checksums)?;
+    let mut i = 0;
+    while i < entries_buf.len() {
+        let (entry_key, value) = read_entry(entries_buf, &mut i).map_err(error)?;
+        match entry_key.cmp(key) {
+            std::cmp::Ordering::Less => (),
+            std::cmp::Ordering::Equal => return Ok(Some(value)),
+            std::cmp::Ordering::Greater => break,
+        }
+    }
+    Ok(None)
+}
+
+// returns the entries of the data block in buf, without the checksum that follows them and which is verified
+// if asked to
+fn block_entries(buf: &[u8], file_no: u64, offset: u64, verify_checksums: bool) -> Result<&[u8]> {
+    let error = |kind| corruption(file_no, "dt", offset, kind);
+    // a block holds at least one entry
+    if buf.len() <= 4 {
+        return Err(error(Corruption::Truncated));
+    }
+    let end = buf.len() - 4;
+    let checksum = read_u32(buf, end).ok_or_else(|| error(Corruption::Truncated))?;
+    if verify_checksums && crc32c::crc32c(&buf[..end]) != checksum {
+        return Err(error(Corruption::ChecksumMismatch));
+    }
+    Ok(&buf[..end])
+}
+
+// reads the entry at offset i of the entries of a block and moves i past it, the key and value being slices of buf
+fn read_entry<'a>(buf: &'a [u8], i: &mut usize) -> std::result::Result<(&'a [u8], TableValue<'a>), Corruption> {
+    let key = read_bytes(buf, i)?;
+    let marker = *buf.get(*i).ok_or(Corruption::Truncated)?;
+    *i += 1;
+    let value = match FromPrimitive::from_u8(marker) {
+        Some(MemValueCode::Value) => TableValue::Value(Cow::Borrowed(read_bytes(buf, i)?)),
+        Some(MemValueCode::Delete) => TableValue::Delete,
+        None => return Err(Corruption::BadMarker),
+    };
+    Ok((key, value))
+}
+
+// reads <len><bytes> at offset i of buf and moves i past it, validating len against the remaining bytes
+fn read_bytes<'a>(buf: &'a [u8], i: &mut usize) -> std::result::Result<&'a [u8], Corruption> {
+    let len = read_u64(buf, *i).ok_or(Corruption::Truncated)?;
+    let start = *i + 8;
+    if len > (buf.len() - start) as u64 {
+        return Err(Corruption::BadLength);
+    }
+    *i = start + len as usize;
+    Ok(&buf[start..*i])
+}
+
+// reads a big-endian u64 at offset i of buf, if buf is long enough
+fn read_u64(buf: &[u8], i: usize) -> Option<u64> {
+    let bytes = buf.get(i..i.checked_add(8)?)?;
+    Cursor::new(bytes).read_u64::<BigEndian>().ok()
+}
+
+// reads a big-endian u32 at offset i of buf, if buf is long enough
+fn read_u32(buf: &[u8], i: usize) -> Option<u32> {
+    let bytes = buf.get(i..i.checked_add(4)?)?;
+    Cursor::new(bytes).read_u32::<BigEndian>().ok()
+}
+
+fn corruption(file_no: u64, file_type: &'static str, offset: u64, kind: Corruption) -> Error {
+    Error::TableCorruptionError {
+        file_no,
+        file_type,
+        offset,
+        kind,
+    }
+}
+
+// memory taken up by a decoded block, where every key and value is held in a vector of its own
+fn block_charge(block: &Block) -> usize {
+    let entries: usize = block
+        .iter()
+        .map(|(key, value)| match value {
+            MemValue::Value(value) => key.capacity() + value.capacity(),
+            MemValue::Delete => key.capacity(),
+        })
+        .sum();
+    mem::size_of::<Block>() + block.capacity() * mem::size_of::<(Vec<u8>, MemValue)>() + entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        block_charge, decode_block, decode_index, BlockCache, Table, TableBuilder, TableValue, INDEX_HEADER_LEN,
+    };
+    use crate::errors::{Corruption, Error};
+    use crate::memtable::MemValue;
+    use std::borrow::Cow;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn build_table(db_name: &str) {
+        fs::create_dir_all(db_name).unwrap();
+        let mut table_builder = TableBuilder::new(db_name, 1, 2, 0);
+        table_builder
+            .add(b"key", &MemValue::Value(b"value".to_vec()))
+            .unwrap();
+        table_builder.add(b"removed", &MemValue::Delete).unwrap();
+        table_builder.flush(false).unwrap();
+    }
+
+    // flips a bit of the byte at offset in the file
+    fn corrupt(file_name: &str, offset: usize) {
+        let mut buf = fs::read(file_name).unwrap();
+        buf[offset] ^= 1;
+        fs::write(file_name, buf).unwrap();
+    }
+
+    #[test]
+    fn test_blocks() {
+        let db_name = "target/testtable4";
+        fs::create_dir_all(db_name).unwrap();
+        let mut table_builder = TableBuilder::new(db_name, 1, 0, 0);
+        for i in 0..1000 {
+            let value = if i % 3 == 0 {
+                MemValue::Delete
+            } else {
+                MemValue::Value(format!("value{:020}", i).into_bytes())
+            };
+            table_builder.add(format!("key{:04}", i * 2).as_bytes(), &value).unwrap();
+        }
+        table_builder.flush(false).unwrap();
+
+        let table = Table::open(db_name, 1, None, false, true).unwrap();
+        assert!(table.num_blocks() > 5);
+        for i in 0..1000 {
+            match table.get(format!("key{:04}", i * 2).as_bytes(), true).unwrap() {
+                Some(TableValue::Value(val)) => assert_eq!(val, format!("value{:020}", i).into_bytes()),
+                Some(TableValue::Delete) => assert_eq!(i % 3, 0),
+                None => panic!("key not found"),
             }
+            assert!(table.get(format!("key{:04}", i * 2 + 1).as_bytes(), true).unwrap().is_none());
+        }
+        assert!(table.get(b"a", true).unwrap().is_none());
+        assert!(table.get(b"z", true).unwrap().is_none());
+        table.verify().unwrap();
+
+        // the block cache is charged the memory of the decoded blocks, larger than their size on disk
+        let block_cache = Arc::new(BlockCache::new(1 << 20));
+        let table = Table::open(db_name, 1, Some(block_cache.clone()), false, true).unwrap();
+        let block = table.read_block(0, true).unwrap();
+        assert_eq!(block_cache.usage().unwrap(), block_charge(&block));
+        assert!(block_cache.usage().unwrap() > table.index[0].len as usize * 2);
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let db_name = "target/testtable5";
+        fs::create_dir_all(db_name).unwrap();
+        let mut table_builder = TableBuilder::new(db_name, 1, 0, 0);
+        for i in 0..1000 {
+            let value = MemValue::Value(format!("value{:020}", i).into_bytes());
+            table_builder.add(format!("key{:04}", i).as_bytes(), &value).unwrap();
+        }
+        table_builder.flush(false).unwrap();
+
+        let tables = [
+            Arc::new(Table::open(db_name, 1, None, false, true).unwrap()),
+            Arc::new(Table::open(db_name, 1, None, true, true).unwrap()),
+        ];
+        // the files stay open or mapped, so the table can be read after they are unlinked
+        fs::remove_dir_all(db_name).unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let table = tables[t % 2].clone();
+                thread::spawn(move || {
+                    for i in (t..1000).step_by(4) {
+                        match table.get(format!("key{:04}", i).as_bytes(), true).unwrap() {
+                            Some(TableValue::Value(val)) => assert_eq!(val, format!("value{:020}", i).into_bytes()),
+                            _ => panic!("key not found"),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_mmap_get() {
+        let db_name = "target/testtable6";
+        build_table(db_name);
+
+        // a value looked up in a mapped table is a slice of the mapping and the block cache is not filled
+        let block_cache = Arc::new(BlockCache::new(1 << 20));
+        let table = Table::open(db_name, 1, Some(block_cache.clone()), true, true).unwrap();
+        match table.get(b"key", true).unwrap() {
+            Some(TableValue::Value(Cow::Borrowed(val))) => assert_eq!(val, b"value"),
+            _ => panic!("value not borrowed from the mapping"),
+        }
+        assert_eq!(table.get(b"removed", true).unwrap(), Some(TableValue::Delete));
+        assert!(table.get(b"kex", true).unwrap().is_none());
+        assert!(table.get(b"kez", true).unwrap().is_none());
+        assert_eq!(block_cache.usage().unwrap(), 0);
+
+        let table = Table::open(db_name, 1, Some(block_cache.clone()), false, true).unwrap();
+        match table.get(b"key", true).unwrap() {
+            Some(TableValue::Value(Cow::Owned(val))) => assert_eq!(val, b"value"),
+            _ => panic!("value not copied from the block"),
+        }
+        assert!(block_cache.usage().unwrap() > 0);
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_data_checksum() {
+        let db_name = "target/testtable1";
+        build_table(db_name);
+        // corrupt the value of the first entry - <key len><"key"><marker><value len><"value">
+        corrupt(&format!("{}/1.dt", db_name), 8 + 3 + 1 + 8 + 1);
+
+        let table = Table::open(db_name, 1, None, false, true).unwrap();
+        match table.get(b"key", true) {
+            Err(Error::TableCorruptionError {
+                file_no: 1,
+                file_type: "dt",
+                offset: 0,
+                kind: Corruption::ChecksumMismatch,
+            }) => (),
+            _ => panic!("corruption not detected"),
+        }
+        match table.key_range(true) {
+            Err(Error::TableCorruptionError {
+                kind: Corruption::ChecksumMismatch,
+                ..
+            }) => (),
+            _ => panic!("corruption not detected"),
+        }
+        // without verification the corrupted value is returned
+        match table.get(b"key", false).unwrap() {
+            Some(TableValue::Value(val)) => assert_eq!(val.as_ref(), b"v`lue"),
+            _ => panic!("value not found"),
+        }
+        assert_eq!(table.key_range(false).unwrap(), Some((b"key".to_vec(), b"removed".to_vec())));
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_index_checksum() {
+        let db_name = "target/testtable2";
+        build_table(db_name);
+        assert_eq!(Table::open(db_name, 1, None, false, true).unwrap().level(), 2);
+        // corrupt the first key of the block, after the header -
+        // <key len><"key"><block offset><block len><checksum>
+        corrupt(&format!("{}/1.ix", db_name), INDEX_HEADER_LEN + 8);
+
+        match Table::open(db_name, 1, None, false, true) {
+            Err(Error::TableCorruptionError {
+                file_no: 1,
+                file_type: "ix",
+                offset: 12,
+                kind: Corruption::ChecksumMismatch,
+            }) => (),
+            _ => panic!("corruption not detected"),
+        }
+        assert!(Table::open(db_name, 1, None, false, false).is_ok());
+
+        // corrupt the level in the header - <level><checksum>
+        build_table(db_name);
+        corrupt(&format!("{}/1.ix", db_name), 7);
+        match Table::open(db_name, 1, None, false, true) {
+            Err(Error::TableCorruptionError {
+                file_no: 1,
+                file_type: "ix",
+                offset: 0,
+                kind: Corruption::ChecksumMismatch,
+            }) => (),
+            _ => panic!("corruption not detected"),
+        }
+        fs::remove_dir_all(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_index() {
+        let db_name = "target/testtable3";
+        build_table(db_name);
+        let index_buf = fs::read(format!("{}/1.ix", db_name)).unwrap();
+        let data_buf = fs::read(format!("{}/1.dt", db_name)).unwrap();
+        let (level, index) = decode_index(&index_buf, 1, true).unwrap();
+        assert_eq!((level, index.len()), (2, 1));
+        assert_eq!(decode_block(&data_buf, 1, 0, true).unwrap().len(), 2);
+
+        // every truncation of a valid file is reported as corruption, but the header alone is a valid empty index
+        for len in 0..index_buf.len() {
+            assert_eq!(decode_index(&index_buf[..len], 1, false).is_err(), len != INDEX_HEADER_LEN);
+        }
+        for len in 0..data_buf.len() {
+            assert!(decode_block(&data_buf[..len], 1, 0, true).is_err());
+        }
+
+        // a huge key length does not allocate
+        let mut huge_key = index_buf.clone();
+        huge_key[INDEX_HEADER_LEN..INDEX_HEADER_LEN + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        match decode_index(&huge_key, 1, false) {
+            Err(Error::TableCorruptionError {
+                kind: Corruption::BadLength,
+                ..
+            }) => (),
+            _ => panic!("corruption not detected"),
+        }
+
+        // unknown value marker
+        let mut bad_marker = data_buf.clone();
+        bad_marker[8 + 3] = 7;
+        match decode_block(&bad_marker, 1, 0, false) {
+            Err(Error::TableCorruptionError {
+                kind: Corruption::BadMarker,
+                ..
+            }) => (),
+            _ => panic!("corruption not detected"),
         }
+        fs::remove_dir_all(db_name).unwrap();
     }
 }
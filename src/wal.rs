@@ -0,0 +1,166 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::Cursor;
+
+use crate::errors::{Error, Result};
+use crate::memtable::{MemTable, MemValue};
+
+// Write-ahead log of a mem_table.
+// Every write is appended to the log before it is applied to the mem_table, so that the
+// mem_table can be rebuilt if the process dies before it is flushed to disk.
+// The log is removed once the mem_table has been flushed to a log table and the manifest records that
+// all logs below the next log number are no longer needed.
+// Records are stored as : <checksum><payload len><payload bytes>
+// where the payload is a sequence of <value marker><key len><key bytes>[<value len><value bytes>]
+pub struct LogWriter {
+    file: File,
+    file_no: u64,
+    // number of records appended since the log was last synced
+    unsynced: usize,
+}
+
+impl LogWriter {
+    // creates a new (empty) log file
+    pub fn create(db_name: &str, file_no: u64) -> Result<LogWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(log_file_name(db_name, file_no))?;
+        Ok(LogWriter {
+            file,
+            file_no,
+            unsynced: 0,
+        })
+    }
+
+    // appends a record containing the given operations to the log and optionally syncs it
+    pub fn add_record(&mut self, ops: &[(Vec<u8>, MemValue)], sync: bool) -> Result<()> {
+        let mut payload = Vec::new();
+        for (key, value) in ops {
+            payload.push(value.encode());
+            encode(&mut payload, key)?;
+            if let MemValue::Value(val) = value {
+                encode(&mut payload, val)?;
+            }
+        }
+
+        let record = encode_record(&payload)?;
+        // the whole record is handed to the OS in a single write so that a crash of the process
+        // can leave at most a partial record at the end of the log
+        self.file.write_all(&record)?;
+        self.unsynced += 1;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // syncs the records appended since the last sync to the disk
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    pub fn unsynced(&self) -> usize {
+        self.unsynced
+    }
+
+    pub fn file_no(&self) -> u64 {
+        self.file_no
+    }
+}
+
+// Replays the log into the mem_table, numbering the operations after last_sequence.
+// Replay stops at the first incomplete or corrupted record, which can only be the result
+// of a write that was interrupted by a crash.
+// Returns the sequence number of the last replayed operation
+pub fn replay(db_name: &str, file_no: u64, mem_table: &MemTable, last_sequence: u64) -> Result<u64> {
+    let mut buf = Vec::new();
+    File::open(log_file_name(db_name, file_no))?.read_to_end(&mut buf)?;
+
+    let mut sequence = last_sequence;
+    for payload in decode_records(&buf) {
+        // the complete record is decoded before it is applied so that a record is never applied partially
+        match decode_record(payload) {
+            Ok(ops) => {
+                mem_table.write(&ops, sequence + 1, None)?;
+                sequence += ops.len() as u64;
+            }
+            Err(_) => break,
+        }
+    }
+    Ok(sequence)
+}
+
+// encodes the payload as a log record - <checksum><payload len><payload bytes>
+pub fn encode_record(payload: &[u8]) -> Result<Vec<u8>> {
+    let mut record = Vec::with_capacity(12 + payload.len());
+    record.write_u32::<BigEndian>(crc32c::crc32c(payload))?;
+    record.write_u64::<BigEndian>(payload.len() as u64)?;
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+// Returns the payloads of the records in buf, up to the first incomplete or corrupted record
+pub fn decode_records(buf: &[u8]) -> Vec<&[u8]> {
+    let mut payloads = Vec::new();
+    let mut i = 0;
+    while buf.len() - i >= 12 {
+        let mut header = Cursor::new(&buf[i..i + 12]);
+        let checksum = header.read_u32::<BigEndian>().unwrap();
+        let len = header.read_u64::<BigEndian>().unwrap();
+        if len > (buf.len() - i - 12) as u64 {
+            break;
+        }
+        let payload = &buf[i + 12..i + 12 + len as usize];
+        if crc32c::crc32c(payload) != checksum {
+            break;
+        }
+        payloads.push(payload);
+        i += 12 + len as usize;
+    }
+    payloads
+}
+
+pub fn log_file_name(db_name: &str, file_no: u64) -> String {
+    format!("{}/{}.wal", db_name, file_no)
+}
+
+// encodes data to on-disk format - <data len><data bytes>
+fn encode(buf: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    buf.write_u64::<BigEndian>(data.len() as u64)?;
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+fn decode(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>> {
+    let len = cursor.read_u64::<BigEndian>()?;
+    let remaining = cursor.get_ref().len() as u64 - cursor.position();
+    if len > remaining {
+        return Err(Error::DBCorruptionError);
+    }
+    let mut data = vec![0; len as usize];
+    cursor.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn decode_record(payload: &[u8]) -> Result<Vec<(Vec<u8>, MemValue)>> {
+    let mut ops = Vec::new();
+    let mut cursor = Cursor::new(payload);
+    while cursor.position() < payload.len() as u64 {
+        let marker = cursor.read_u8()?;
+        let key = decode(&mut cursor)?;
+        let value = match marker {
+            0 => MemValue::Value(decode(&mut cursor)?),
+            1 => MemValue::Delete,
+            _ => return Err(Error::DBCorruptionError),
+        };
+        ops.push((key, value));
+    }
+    Ok(ops)
+}
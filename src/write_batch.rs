@@ -0,0 +1,48 @@
+use crate::memtable::MemValue;
+
+/// A batch of puts and deletes that is applied to the db atomically.
+/// Readers see either none or all of the writes of a batch, and a batch is never split
+/// between two log tables
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, MemValue)>,
+}
+
+impl WriteBatch {
+    /// Returns an empty WriteBatch
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    /// Adds the insertion of a key-value pair to the batch
+    pub fn put<S: AsRef<[u8]>>(&mut self, key: S, value: S) {
+        self.ops.push((
+            key.as_ref().to_vec(),
+            MemValue::Value(value.as_ref().to_vec()),
+        ));
+    }
+
+    /// Adds the deletion of a key to the batch
+    pub fn delete<S: AsRef<[u8]>>(&mut self, key: S) {
+        self.ops.push((key.as_ref().to_vec(), MemValue::Delete));
+    }
+
+    /// Returns the number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if the batch has no operations
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Removes all the operations from the batch
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub(crate) fn ops(&self) -> &[(Vec<u8>, MemValue)] {
+        &self.ops
+    }
+}